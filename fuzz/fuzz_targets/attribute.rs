@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use stun::Attribute;
+
+// Address decoding on a raw value, with the first 16 bytes as the
+// transaction id.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 16 {
+        return;
+    }
+    let (trans_id, value) = data.split_at(16);
+    let a = Attribute {
+        s_type: stun::ATTRIBUTE_XOR_MAPPED_ADDRESS,
+        length: value.len() as u16,
+        value: value.to_vec(),
+    };
+    let _ = a.raw_addr();
+    let _ = a.get_xor_addr(trans_id.to_vec());
+});
@@ -0,0 +1,27 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use stun::response::Response;
+use stun::{DecodeMode, Packet};
+
+fuzz_target!(|data: &[u8]| {
+    for mode in [DecodeMode::Strict, DecodeMode::Lenient] {
+        let pkt = match Packet::decode(data, mode) {
+            Ok(pkt) => pkt,
+            Err(_) => continue,
+        };
+        // Everything a client reads from a response.
+        let resp = Response::new(pkt.clone(), &"192.0.2.1:5000".parse().unwrap());
+        let _ = resp.ecn_verdict();
+        let _ = pkt.message_type();
+        let _ = pkt.get_response_addr();
+        let _ = stun::secret::error_code(&pkt);
+        let _ = stun::secret::check_message_integrity(data, b"key");
+
+        // Whatever decodes must encode to something that decodes the same.
+        let bytes = pkt.bytes();
+        let again = Packet::decode(&bytes, DecodeMode::Lenient).expect("re-encoded packet");
+        assert_eq!(again.attributes, pkt.attributes);
+        assert_eq!(again.bytes(), bytes);
+    }
+});
@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use stun::demux::classify;
+use stun::{DecodeMode, Packet, PacketRef};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = classify(data);
+    // Both parsers agree on what they accept.
+    let (pkt, decoded) = match (
+        PacketRef::parse(data),
+        Packet::decode(data, DecodeMode::Lenient),
+    ) {
+        (Ok(pkt), Ok(decoded)) => (pkt, decoded),
+        (Err(_), Err(_)) => return,
+        (pkt, decoded) => panic!("PacketRef: {:?}, Packet::decode: {:?}", pkt, decoded),
+    };
+    let _ = (pkt.types(), pkt.length(), pkt.trans_id());
+    for a in pkt.attributes() {
+        let _ = pkt.attribute(a.s_type);
+    }
+    assert_eq!(pkt.to_packet().attributes, decoded.attributes);
+});
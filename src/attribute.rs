@@ -1,15 +1,28 @@
 use crate::ATTRIBUTE_FINGERPRINT;
 use crate::FINGERPRINT;
-use crate::{ ATTRIBUTE_CHANGE_REQUEST, ATTRIBUTE_FAMILY_IPV4, ATTRIBUTE_SOFTWARE};
+use crate::{
+    Version, ATTRIBUTE_CHANGE_REQUEST, ATTRIBUTE_MESSAGE_INTEGRITY, ATTRIBUTE_PADDING,
+    ATTRIBUTE_RESPONSE_PORT, ATTRIBUTE_SOFTWARE, ATTRIBUTE_TRANSACTION_TRANSMIT_COUNTER,
+    ATTRIBUTE_USERNAME,
+};
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
+use std::net::{IpAddr, SocketAddr};
 
 use super::utils;
 use super::Host;
 use super::Packet;
+use crate::host;
 extern crate crc32fast;
 use byteorder::{BigEndian, ByteOrder};
+use hmac::{Hmac, Mac};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
 
+type HmacSha1 = Hmac<Sha1>;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Attribute {
     pub s_type: u16,
@@ -19,7 +32,7 @@ pub struct Attribute {
 
 impl Attribute {
     pub fn new(s_type: u16, value: &[u8]) -> Self {
-        let padded_value = utils::padding(&value);
+        let padded_value = utils::padding(value);
         Attribute {
             s_type,
             length: padded_value.len() as u16,
@@ -52,91 +65,222 @@ impl Attribute {
         Attribute::new(ATTRIBUTE_CHANGE_REQUEST, &value)
     }
 
+    // RFC 5780 PADDING: `len` zero bytes, rounded up to a multiple of 4.
+    pub fn new_padding_attribute(len: usize) -> Attribute {
+        Attribute::new(ATTRIBUTE_PADDING, &vec![0u8; len])
+    }
+
+    // RFC 5780 RESPONSE-PORT: 16-bit port followed by 16 bits of padding.
+    pub fn new_response_port_attribute(port: u16) -> Attribute {
+        let mut value = vec![0u8; 4];
+        BigEndian::write_u16(&mut value[..2], port);
+        Attribute::new(ATTRIBUTE_RESPONSE_PORT, &value)
+    }
+
+    // RFC 7982 TRANSACTION_TRANSMIT_COUNTER: 16 reserved bits, then the
+    // transmission number of the request and the response count.
+    pub fn new_transmit_counter_attribute(req: u8, resp: u8) -> Attribute {
+        Attribute::new(ATTRIBUTE_TRANSACTION_TRANSMIT_COUNTER, &[0, 0, req, resp])
+    }
+
+    pub fn new_username_attribute(username: &str) -> Attribute {
+        Attribute::new(ATTRIBUTE_USERNAME, username.as_bytes())
+    }
+
+    // HMAC-SHA1 over `pkt.bytes()`, whose length must already count the
+    // 24 bytes of this attribute. RFC 3489 pads the input with zeros to a
+    // multiple of 64 bytes.
+    pub fn new_message_integrity_attribute(pkt: &Packet, key: &[u8]) -> Attribute {
+        let classic = pkt.version() == Version::RFC3489;
+        Attribute::new(
+            ATTRIBUTE_MESSAGE_INTEGRITY,
+            &message_integrity(&pkt.bytes(), key, classic),
+        )
+    }
+
     //      0                   1                   2                   3
     //      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
     //     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-    //     |x x x x x x x x|    Family     |         X-Port                |
+    //     |0 0 0 0 0 0 0 0|    Family     |           Port                |
     //     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-    //     |                X-Address (Variable)
+    //     |                 Address (32 bits or 128 bits)                 |
     //     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-    pub fn get_xor_addr(&self, trans_id: Vec<u8>) -> Host {
-        let mut xor_ip = vec![0u8, 16];
-        for i in 0..self.value.len() - 4 {
-            xor_ip[i] = self.value[i + 4] ^ trans_id[i]
-        }
-        let family = self.value[1] as u16;
-        let port = ((self.value[2] as u16) << 8) | (self.value[3] as u16);
-
-        if family == ATTRIBUTE_FAMILY_IPV4 {
-            xor_ip = xor_ip[0..4].to_vec()
-        }
+    // MAPPED-ADDRESS and the other plain address attributes: RESPONSE-ADDRESS,
+    // SOURCE-ADDRESS, CHANGED-ADDRESS, REFLECTED-FROM and OTHER-ADDRESS.
+    pub fn new_addr_attribute(s_type: u16, addr: &SocketAddr) -> Attribute {
+        Attribute::new(s_type, &encode_addr(addr, &[0u8; 16]))
+    }
 
-        let x = ((trans_id[2] as u16) << 8) | (trans_id[3] as u16);
-        Host {
-            family,
-            ip: format!(
-                "{}",
-                Ipv4Addr::new(xor_ip[0], xor_ip[1], xor_ip[2], xor_ip[3])
-            ),
-            port: port ^ x,
-        }
+    // XOR-MAPPED-ADDRESS: the port is XORed with the top 16 bits of the
+    // magic cookie, the address with the cookie and the transaction id.
+    pub fn new_xor_addr_attribute(
+        s_type: u16,
+        addr: &SocketAddr,
+        trans_id: &[u8; 16],
+    ) -> Attribute {
+        Attribute::new(s_type, &encode_addr(addr, trans_id))
     }
 
-    pub fn raw_addr(&mut self) -> Host {
-        let family = self.value[1] as u16;
-        let port = ((self.value[2] as u16) << 8) | (self.value[3] as u16);
+    //      0                   1                   2                   3
+    //      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+    //     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    //     |x x x x x x x x|    Family     |         X-Port                |
+    //     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    //     |                X-Address (Variable)
+    //     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    pub fn get_xor_addr(&self, trans_id: Vec<u8>) -> Result<Host, String> {
+        decode_addr(&self.value, &utils::convert_vec_to_u8_array(&trans_id))
+    }
 
-        let ip = if family == ATTRIBUTE_FAMILY_IPV4 {
-            self.value = self.value[..8].to_vec();
-            format!(
-                "{}.{}.{}.{}",
-                self.value[4], self.value[5], self.value[6], self.value[7]
-            )
-        } else {
-            Ipv6Addr::from(convert_vec_to_u8_array(&self.value)).to_string()
-        };
+    pub fn raw_addr(&self) -> Result<Host, String> {
+        decode_addr(&self.value, &[0u8; 16])
+    }
+}
 
-        Host { family, port, ip }
+pub(crate) fn message_integrity(text: &[u8], key: &[u8], classic: bool) -> [u8; 20] {
+    let mut mac = HmacSha1::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(text);
+    if classic && !text.len().is_multiple_of(64) {
+        mac.update(&[0u8; 64][..64 - text.len() % 64]);
     }
+    mac.finalize().into_bytes().into()
 }
 
-fn convert_vec_to_u8_array(vec: &Vec<u8>) -> [u8; 16] {
-    let mut arry: [u8; 16] = [0; 16];
+// `mask` is XORed over port and address, all zeros for the plain
+// attributes.
+fn encode_addr(addr: &SocketAddr, mask: &[u8; 16]) -> Vec<u8> {
+    let mut value = vec![0u8; 4];
+    BigEndian::write_u16(
+        &mut value[2..4],
+        addr.port() ^ BigEndian::read_u16(&mask[..2]),
+    );
+    value[1] = host::family(&addr.ip()) as u8;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    value.extend(ip.iter().zip(mask.iter()).map(|(b, m)| b ^ m));
+    value
+}
 
-    for i in 0..vec.len().min(16) {
-        arry[i] = vec[i];
+// Values come off the wire: the family and length are checked before any
+// byte is read.
+fn decode_addr(value: &[u8], mask: &[u8; 16]) -> Result<Host, String> {
+    if value.len() < 4 {
+        return Err(format!(
+            "Address attribute too short: {} bytes",
+            value.len()
+        ));
+    }
+    let family = value[1] as u16;
+    let size = host::family_len(family)
+        .ok_or_else(|| format!("Unknown address family {:#04x}", family))?;
+    let address = value
+        .get(4..4 + size)
+        .ok_or_else(|| format!("Address attribute too short: {} bytes", value.len()))?;
+    let port = BigEndian::read_u16(&value[2..4]) ^ BigEndian::read_u16(&mask[..2]);
+    let mut octets = [0u8; 16];
+    for (i, (b, m)) in address.iter().zip(mask.iter()).enumerate() {
+        octets[i] = b ^ m;
     }
-    arry
+    let ip = if size == 4 {
+        IpAddr::V4(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+
+    Ok(Host::from(SocketAddr::new(ip, port)))
 }
 
 #[cfg(test)]
 mod tests {
-
-    use std::fmt;
-
     use super::*;
 
     #[test]
     fn soft_name_test() {
         let result = Attribute::new_software_attribute("版本2");
-        print!("{:?}", result)
+        assert_eq!(result.s_type, ATTRIBUTE_SOFTWARE);
+        assert_eq!(result.length, 8);
+        assert_eq!(&result.value[..7], "版本2".as_bytes());
+        assert_eq!(result.value[7], 0);
     }
 
     #[test]
     fn change_req_test() {
         let result = Attribute::new_change_req_attribute(true, true);
-        print!("{:?}", result)
+        assert_eq!(result.s_type, ATTRIBUTE_CHANGE_REQUEST);
+        assert_eq!(result.value, vec![0, 0, 0, 0x06]);
+        let result = Attribute::new_change_req_attribute(false, true);
+        assert_eq!(result.value, vec![0, 0, 0, 0x02]);
+    }
+
+    #[test]
+    fn padding_test() {
+        let result = Attribute::new_padding_attribute(5);
+        assert_eq!(result.length, 8);
+        assert_eq!(result.value, vec![0u8; 8]);
+    }
+
+    #[test]
+    fn response_port_test() {
+        let result = Attribute::new_response_port_attribute(3479);
+        assert_eq!(result.value, vec![0x0d, 0x97, 0, 0]);
     }
 
     #[test]
     fn test_raw_addr_ipv6() {
-        let mut my_struct = Attribute {
+        let my_struct = Attribute {
             s_type: 1,
             length: 1,
-            value: vec![0, 10, 0, 1, 0xdb, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            value: vec![
+                0, 2, 0, 1, 0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+            ],
         };
 
-        let result = my_struct.raw_addr();
-        println!("{:?}", result.ip)
+        let result = my_struct.raw_addr().unwrap();
+        assert_eq!(result.ip().to_string(), "2001:db8::1");
+        assert_eq!(result.port(), 1);
+    }
+
+    #[test]
+    fn addr_attribute_test() {
+        let addr: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let a = Attribute::new_addr_attribute(crate::ATTRIBUTE_MAPPED_ADDRESS, &addr);
+        assert_eq!(a.value, vec![0, 1, 0x80, 0x55, 192, 0, 2, 1]);
+        assert_eq!(a.raw_addr().unwrap().string(), "192.0.2.1:32853");
+    }
+
+    #[test]
+    fn xor_addr_attribute_test() {
+        // RFC 5769 section 2.2 and 2.3.
+        let trans_id = [
+            0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87,
+            0xdf, 0xae,
+        ];
+        let addr: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let a = Attribute::new_xor_addr_attribute(
+            crate::ATTRIBUTE_XOR_MAPPED_ADDRESS,
+            &addr,
+            &trans_id,
+        );
+        assert_eq!(a.value, vec![0, 1, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        assert_eq!(
+            a.get_xor_addr(trans_id.to_vec()).unwrap().string(),
+            "192.0.2.1:32853"
+        );
+
+        let addr: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let a = Attribute::new_xor_addr_attribute(
+            crate::ATTRIBUTE_XOR_MAPPED_ADDRESS,
+            &addr,
+            &trans_id,
+        );
+        assert_eq!(&a.value[..8], &[0, 2, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa]);
+        assert_eq!(
+            a.get_xor_addr(trans_id.to_vec()).unwrap().string(),
+            "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+        );
     }
 }
@@ -0,0 +1,185 @@
+use std::net::SocketAddr;
+
+use tracing::{debug, warn};
+
+use crate::{Behavior, Host, NATBehavior, Response, Transport};
+
+use super::Client;
+
+// NAT behavior discovery (RFC 5780 section 4.3 and 4.4).
+// Needs a server that reports OTHER-ADDRESS (or CHANGED-ADDRESS) and
+// honours CHANGE-REQUEST.
+
+fn mapped(resp: &Response) -> Result<Host, String> {
+    resp.mapped_addr
+        .ok_or_else(|| "Server error: no mapped address".to_string())
+}
+
+fn other(resp: &Response) -> Result<SocketAddr, String> {
+    resp.other_addr
+        .or(resp.changed_addr)
+        .map(SocketAddr::from)
+        .ok_or_else(|| "Server error: no other address".to_string())
+}
+
+impl Client {
+    // Mapping behavior: compares the mapped address seen by the primary
+    // address, the alternate IP and the alternate IP and port.
+    pub fn mapping_behavior(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+    ) -> Result<Behavior, String> {
+        // Test I
+        let resp = self.test(conn, addr)?;
+        self.mapping_after(conn, addr, &resp)
+    }
+
+    // Mapping behavior from the response to Test I.
+    fn mapping_after(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+        resp: &Response,
+    ) -> Result<Behavior, String> {
+        if resp.identical {
+            return Ok(Behavior::BehaviorTypeEndpoint);
+        }
+        let mapped1 = mapped(resp)?;
+        let other_addr = other(resp)?;
+
+        // Test II: alternate IP, primary port.
+        let addr2 = SocketAddr::new(other_addr.ip(), addr.port());
+        let mapped2 = mapped(&self.test(conn, addr2)?)?;
+        if mapped1 == mapped2 {
+            return Ok(Behavior::BehaviorTypeEndpoint);
+        }
+
+        // Test III: alternate IP and port.
+        let mapped3 = mapped(&self.test(conn, other_addr)?)?;
+        if mapped2 == mapped3 {
+            return Ok(Behavior::BehaviorTypeAddr);
+        }
+        Ok(Behavior::BehaviorTypeAddrAndPort)
+    }
+
+    // Filtering behavior: whether responses from the alternate IP and port,
+    // or from the alternate port only, get through.
+    pub fn filtering_behavior(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+    ) -> Result<Behavior, String> {
+        // Test I
+        let resp = self.test(conn, addr)?;
+        self.filtering_after(conn, addr, &resp)
+    }
+
+    // Filtering behavior from the response to Test I.
+    fn filtering_after(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+        resp: &Response,
+    ) -> Result<Behavior, String> {
+        other(resp)?;
+
+        // Test II: response from the alternate IP and port.
+        if self.test_change_both(conn, addr).is_ok() {
+            return Ok(Behavior::BehaviorTypeEndpoint);
+        }
+
+        // Test III: response from the alternate port.
+        if self.test_change_port(conn, addr).is_ok() {
+            return Ok(Behavior::BehaviorTypeAddr);
+        }
+        Ok(Behavior::BehaviorTypeAddrAndPort)
+    }
+
+    pub fn behavior_discover(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+    ) -> Result<NATBehavior, String> {
+        self.behavior_discover_response(conn, addr)
+            .map(|(behavior, _)| behavior)
+    }
+
+    // Also returns the response to Test I, with the mapped address. Both
+    // behaviors start from that one Test I.
+    pub fn behavior_discover_response(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+    ) -> Result<(NATBehavior, Response), String> {
+        let resp = self.test(conn, addr)?;
+        // Filtering first: the mapping tests send to the alternate address,
+        // which would let its responses through any filter afterwards.
+        let filtering = self.filtering_after(conn, addr, &resp)?;
+        let mapping = self.mapping_after(conn, addr, &resp)?;
+        Ok((NATBehavior::new(mapping, filtering), resp))
+    }
+
+    // Binding request whose response must come from the address the
+    // CHANGE-REQUEST flags ask for.
+    fn send_with_log(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+        change_ip: bool,
+        change_port: bool,
+    ) -> Result<Response, String> {
+        let resp = match self.send_bind_req(conn, addr, change_ip, change_port) {
+            Ok(resp) => resp,
+            Err(e) => {
+                debug!(server = %addr, change_ip, change_port, error = %e, "no response");
+                if !change_ip && !change_port {
+                    return Err("NAT BLOCKED".to_string());
+                }
+                return Err(e);
+            }
+        };
+
+        debug!(
+            server = %addr,
+            change_ip,
+            change_port,
+            from = ?resp.server_addr.as_ref().map(|h| h.string()),
+            mapped = ?resp.mapped_addr.as_ref().map(|h| h.string()),
+            "response received"
+        );
+        if let Some(h) = resp.server_addr {
+            if !addr_compare(h, addr, change_ip, change_port) {
+                warn!(server = %addr, change_ip, change_port, "response from unexpected address");
+                return Err("Server error: response IP/port".to_string());
+            }
+        }
+        Ok(resp)
+    }
+
+    pub fn test(&self, conn: &dyn Transport, addr: SocketAddr) -> Result<Response, String> {
+        self.send_with_log(conn, addr, false, false)
+    }
+
+    pub fn test_change_port(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+    ) -> Result<Response, String> {
+        self.send_with_log(conn, addr, false, true)
+    }
+
+    pub fn test_change_both(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+    ) -> Result<Response, String> {
+        self.send_with_log(conn, addr, true, true)
+    }
+}
+
+fn addr_compare(host: Host, addr: SocketAddr, change_ip: bool, change_port: bool) -> bool {
+    let is_ip_change = host.ip() != addr.ip();
+    let is_port_change = host.port() != addr.port();
+    is_ip_change == change_ip && is_port_change == change_port
+}
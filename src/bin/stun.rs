@@ -0,0 +1,196 @@
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+
+use stun::{Client, InterfaceFilter, Response, TestStep, DEFAULT_SERVER_ADDR};
+
+const USAGE: &str = "Usage: stun [OPTIONS]
+
+Reports the NAT type and the mapped address of this host.
+
+Options:
+  -s, --server <HOST:PORT>   STUN server [default: stun.ekiga.net:3478]
+  -b, --bind <IP:PORT>       Local address to bind [default: 0.0.0.0:0]
+  -n, --software <NAME>      SOFTWARE attribute value [default: stun]
+  -m, --mode <MODE>          rfc3489, rfc5780, binding or interfaces [default: rfc3489]
+  -v, --verbose              Print more details, repeat for packet dumps
+  -h, --help                 Print this help";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Rfc3489,
+    Rfc5780,
+    Binding,
+    Interfaces,
+}
+
+struct Options {
+    server: String,
+    bind: SocketAddr,
+    software: String,
+    mode: Mode,
+    verbose: u8,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options {
+        server: DEFAULT_SERVER_ADDR.to_string(),
+        bind: "0.0.0.0:0".parse().unwrap(),
+        software: "stun".to_string(),
+        mode: Mode::Rfc3489,
+        verbose: 0,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("Missing value for {}", name))
+        };
+        match arg.as_str() {
+            "-s" | "--server" => opts.server = value(arg)?,
+            "-b" | "--bind" => {
+                let bind = value(arg)?;
+                opts.bind = bind
+                    .parse()
+                    .map_err(|_| format!("Invalid bind address {}", bind))?;
+            }
+            "-n" | "--software" => opts.software = value(arg)?,
+            "-m" | "--mode" => {
+                opts.mode = match value(arg)?.as_str() {
+                    "rfc3489" => Mode::Rfc3489,
+                    "rfc5780" => Mode::Rfc5780,
+                    "binding" => Mode::Binding,
+                    "interfaces" => Mode::Interfaces,
+                    m => return Err(format!("Unknown mode {}", m)),
+                }
+            }
+            "--verbose" => opts.verbose += 1,
+            v if v.len() > 1 && v.starts_with('-') && v[1..].chars().all(|c| c == 'v') => {
+                opts.verbose = opts.verbose.saturating_add((v.len() - 1) as u8);
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            a => return Err(format!("Unknown argument {}", a)),
+        }
+    }
+    Ok(opts)
+}
+
+fn print_response(resp: &Response, verbose: u8) {
+    let show = |h: &Option<stun::Host>| h.as_ref().map_or("-".to_string(), |h| h.string());
+    println!("Mapped address: {}", show(&resp.mapped_addr));
+    println!(
+        "Changed address: {}",
+        show(&resp.changed_addr.or(resp.other_addr))
+    );
+    if verbose > 0 {
+        println!("Server address: {}", show(&resp.server_addr));
+    }
+    if verbose > 1 {
+        println!("{:#?}", resp.packet);
+    }
+}
+
+// The first test of an RFC 3489 discovery, as print_response does.
+fn print_step(step: &TestStep, verbose: u8) {
+    let show = |h: &Option<stun::Host>| h.as_ref().map_or("-".to_string(), |h| h.string());
+    println!("Mapped address: {}", show(&step.mapped_addr));
+    println!(
+        "Changed address: {}",
+        show(&step.changed_addr.or(step.other_addr))
+    );
+    if verbose > 0 {
+        println!("Server address: {}", show(&step.response_from));
+    }
+}
+
+// Mapped address of every uplink, for multi-homed hosts.
+fn run_interfaces(client: &Client) -> Result<(), String> {
+    let mappings = client
+        .interface_mappings(&InterfaceFilter::default())
+        .map_err(|e| e.to_string())?;
+    for m in mappings {
+        match m.mapped_addr {
+            Ok(h) if !m.bound_to_device => println!(
+                "{} {}: {} (not bound to the device, may be the default route's)",
+                m.interface.name,
+                m.interface.ip,
+                h.string()
+            ),
+            Ok(h) => println!("{} {}: {}", m.interface.name, m.interface.ip, h.string()),
+            Err(e) => println!("{} {}: error: {}", m.interface.name, m.interface.ip, e),
+        }
+    }
+    Ok(())
+}
+
+fn run(opts: &Options) -> Result<(), String> {
+    let client = Client::new(
+        opts.server.clone(),
+        opts.bind.ip().to_string(),
+        opts.bind.port(),
+        opts.software.clone(),
+    )
+    .map_err(|e| e.to_string())?;
+    if opts.mode == Mode::Interfaces {
+        return run_interfaces(&client);
+    }
+    let addr = client.resolve_server(&opts.server)?;
+    let conn = client.conn.try_clone().map_err(|e| e.to_string())?;
+    if opts.verbose > 0 {
+        println!("Server: {} ({})", opts.server, addr);
+        println!(
+            "Local address: {}",
+            conn.local_addr().map_err(|e| e.to_string())?
+        );
+    }
+
+    match opts.mode {
+        Mode::Interfaces => {}
+        Mode::Binding => {
+            let resp = client.send_bind_req(&conn, addr, false, false)?;
+            print_response(&resp, opts.verbose);
+        }
+        Mode::Rfc3489 => {
+            let discovery = client.discover()?;
+            if let Some(step) = discovery.report.steps.first() {
+                print_step(step, opts.verbose);
+            }
+            println!(
+                "NAT Type: {} ({:?})",
+                discovery.nat.description(),
+                discovery.nat
+            );
+            discovery.mapped_addr?;
+        }
+        Mode::Rfc5780 => {
+            let (behavior, resp) = client.behavior_discover_response(&conn, addr)?;
+            print_response(&resp, opts.verbose);
+            println!("Mapping behavior: {}", behavior.mapping().description());
+            println!("Filtering behavior: {}", behavior.filtering().description());
+            if let Some(nat) = behavior.description() {
+                println!("NAT Type: {}", nat);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = match parse_args(&args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&opts) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
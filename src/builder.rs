@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+
+use crate::net::DEFAULT_RECV_BUFFER_SIZE;
+use crate::{Credentials, Ecn, HealthStore, RetransmitPolicy, ServerList};
+
+use super::Client;
+
+// Address family to use when a server resolves to both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+// Configures a Client before binding its socket.
+//
+// Defaults: no server (Client::servers falls back to DEFAULT_SERVER_ADDR),
+// an ephemeral port on the unspecified address of the preferred family, no
+// SOFTWARE, FINGERPRINT on, no credentials, RFC 5389 requests without ECN
+// or TRANSACTION-TRANSMIT-COUNTER.
+pub struct ClientBuilder {
+    servers: Vec<String>,
+    bind: Option<SocketAddr>,
+    socket: Option<UdpSocket>,
+    software_name: Option<String>,
+    fingerprint: bool,
+    classic: bool,
+    ecn: Option<Ecn>,
+    transmit_counter: bool,
+    credentials: Option<Credentials>,
+    retransmit_policy: RetransmitPolicy,
+    family: Option<AddressFamily>,
+    recv_buffer_size: usize,
+    health: Option<HealthStore>,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+}
+
+impl ClientBuilder {
+    pub fn new() -> ClientBuilder {
+        ClientBuilder {
+            servers: Vec::new(),
+            bind: None,
+            socket: None,
+            software_name: None,
+            fingerprint: true,
+            classic: false,
+            ecn: None,
+            transmit_counter: false,
+            credentials: None,
+            retransmit_policy: RetransmitPolicy::default(),
+            family: None,
+            recv_buffer_size: DEFAULT_RECV_BUFFER_SIZE,
+            health: None,
+        }
+    }
+
+    // Adds a server ("host:port"), in order of preference.
+    pub fn server(mut self, server: &str) -> ClientBuilder {
+        self.servers.push(server.to_string());
+        self
+    }
+
+    pub fn servers(mut self, servers: Vec<String>) -> ClientBuilder {
+        self.servers.extend(servers);
+        self
+    }
+
+    // Adds the servers of `list`, healthiest first. The client keeps a copy
+    // of `health` and records every plain Binding request in it, see
+    // Client::health.
+    pub fn server_list(mut self, list: &ServerList, health: &HealthStore) -> ClientBuilder {
+        self.servers.extend(list.ranked(health));
+        self.health = Some(health.clone());
+        self
+    }
+
+    pub fn bind(mut self, addr: SocketAddr) -> ClientBuilder {
+        self.bind = Some(addr);
+        self
+    }
+
+    // Uses an already bound socket instead of binding one.
+    pub fn socket(mut self, socket: UdpSocket) -> ClientBuilder {
+        self.socket = Some(socket);
+        self
+    }
+
+    // SOFTWARE attribute value. Without it requests carry no SOFTWARE.
+    pub fn software(mut self, name: &str) -> ClientBuilder {
+        self.software_name = Some(name.to_string());
+        self
+    }
+
+    pub fn fingerprint(mut self, fingerprint: bool) -> ClientBuilder {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    // RFC 3489 requests: no magic cookie, see Client::classic.
+    pub fn classic(mut self, classic: bool) -> ClientBuilder {
+        self.classic = classic;
+        self
+    }
+
+    // ECN codepoint of requests, which then carry ECN-CHECK. It is set on
+    // the socket for each request, don't share the socket with other senders.
+    pub fn ecn(mut self, ecn: Ecn) -> ClientBuilder {
+        self.ecn = Some(ecn);
+        self
+    }
+
+    // TRANSACTION-TRANSMIT-COUNTER on every transmission (RFC 7982).
+    pub fn transmit_counter(mut self, transmit_counter: bool) -> ClientBuilder {
+        self.transmit_counter = transmit_counter;
+        self
+    }
+
+    pub fn credentials(mut self, credentials: Credentials) -> ClientBuilder {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn retransmit_policy(mut self, policy: RetransmitPolicy) -> ClientBuilder {
+        self.retransmit_policy = policy;
+        self
+    }
+
+    pub fn family(mut self, family: AddressFamily) -> ClientBuilder {
+        self.family = Some(family);
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, size: usize) -> ClientBuilder {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    pub fn build(self) -> io::Result<Client> {
+        let socket = match self.socket {
+            Some(socket) => socket,
+            None => {
+                let bind = self.bind.unwrap_or(match self.family {
+                    Some(AddressFamily::V6) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                    _ => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                });
+                UdpSocket::bind(bind)?
+            }
+        };
+        let local = socket.local_addr()?;
+
+        Ok(Client {
+            server_addrs: self.servers,
+            local_ip: local.ip().to_string(),
+            local_port: local.port(),
+            software_name: self.software_name.unwrap_or_default(),
+            conn: Arc::new(socket),
+            recv_buffer_size: self.recv_buffer_size,
+            retransmit_policy: self.retransmit_policy,
+            classic: self.classic,
+            credentials: self.credentials,
+            ecn: self.ecn,
+            transmit_counter: self.transmit_counter,
+            fingerprint: self.fingerprint,
+            family: self.family,
+            rtt_estimates: Mutex::new(HashMap::new()),
+            health: self.health.map(Mutex::new),
+            server_names: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, ATTRIBUTE_FINGERPRINT, ATTRIBUTE_SOFTWARE};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn builder_test() {
+        let server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve_one());
+
+        let policy = RetransmitPolicy {
+            initial_rto: Duration::from_millis(100),
+            ..RetransmitPolicy::default()
+        };
+        let client = Client::builder()
+            .server(&server_addr.to_string())
+            .bind("127.0.0.1:0".parse().unwrap())
+            .fingerprint(false)
+            .retransmit_policy(policy)
+            .recv_buffer_size(4096)
+            .build()
+            .unwrap();
+        assert_eq!(client.servers(), vec![server_addr.to_string()]);
+        assert_eq!(client.local_ip, "127.0.0.1");
+        assert_eq!(client.recv_buffer_size, 4096);
+        assert_eq!(
+            client.retransmit_policy.initial_rto,
+            Duration::from_millis(100)
+        );
+
+        // No SOFTWARE, no FINGERPRINT.
+        let req = client.new_bind_req(false, false, Vec::new());
+        assert!(req
+            .attributes
+            .iter()
+            .all(|a| a.s_type != ATTRIBUTE_SOFTWARE && a.s_type != ATTRIBUTE_FINGERPRINT));
+
+        let resp = client
+            .send_bind_req(&client.conn, server_addr, false, false)
+            .unwrap();
+        assert_eq!(
+            resp.mapped_addr.unwrap().addr,
+            client.conn.local_addr().unwrap()
+        );
+    }
+
+    #[test]
+    fn builder_socket_test() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = socket.local_addr().unwrap();
+        let client = Client::builder()
+            .socket(socket)
+            .software("stun")
+            .family(AddressFamily::V4)
+            .credentials(Credentials::new("user", "password"))
+            .build()
+            .unwrap();
+        assert_eq!(client.conn.local_addr().unwrap(), local);
+        assert_eq!(client.local_port, local.port());
+        assert_eq!(client.family, Some(AddressFamily::V4));
+        let req = client.new_bind_req(false, false, Vec::new());
+        assert_eq!(req.attributes[0].s_type, ATTRIBUTE_SOFTWARE);
+        assert_eq!(req.attributes.last().unwrap().s_type, ATTRIBUTE_FINGERPRINT);
+
+        let client = Client::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .classic(true)
+            .ecn(Ecn::Ect1)
+            .transmit_counter(true)
+            .build()
+            .unwrap();
+        assert!(client.classic && client.transmit_counter);
+        assert_eq!(client.ecn, Some(Ecn::Ect1));
+    }
+}
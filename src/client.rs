@@ -1,19 +1,33 @@
-use std::io;
-use std::io::Result;
-use std::net::{ToSocketAddrs, UdpSocket};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
 
+use crate::builder::{AddressFamily, ClientBuilder};
+use crate::ecn::Ecn;
+use crate::retransmit::{RetransmitPolicy, RttEstimate};
+use crate::secret::Credentials;
+use crate::servers::HealthStore;
 use crate::DEFAULT_SERVER_ADDR;
 
-use super::Host;
-use super::NAT;
-
 pub struct Client {
-    pub server_addr: String,
+    pub server_addrs: Vec<String>, // 多服务器发现时使用的服务器列表，按优先级排列
     pub local_ip: String,
     pub local_port: u16, // Rust 中端口号通常是 u16 类型
     pub software_name: String,
     pub conn: Arc<UdpSocket>, // 使用 Arc 来允许多个线程间共享 socket
+
+    pub recv_buffer_size: usize,             // 接收缓冲区大小
+    pub retransmit_policy: RetransmitPolicy, // 重传策略
+    pub classic: bool,                       // RFC 3489 兼容模式：无 magic cookie 等
+    pub credentials: Option<Credentials>,    // 共享密钥，用于 MESSAGE-INTEGRITY
+    pub ecn: Option<Ecn>,                    // 请求的 ECN 标记，带 ECN-CHECK；设置时 conn 不可共享
+    pub transmit_counter: bool,              // 每次发送带传输计数 (RFC 7982)
+    pub fingerprint: bool,                   // 请求带 FINGERPRINT
+    pub family: Option<AddressFamily>,       // 服务器有多个地址时优先的地址族
+
+    pub(crate) rtt_estimates: Mutex<HashMap<SocketAddr, RttEstimate>>, // 每个服务器的 RTT 估计
+    pub(crate) health: Option<Mutex<HealthStore>>,                     // 服务器健康记录
+    pub(crate) server_names: Mutex<HashMap<SocketAddr, String>>,       // 地址对应的服务器名
 }
 
 impl Client {
@@ -27,36 +41,33 @@ impl Client {
 
         let socket = UdpSocket::bind(&address)?;
 
-        Ok(Client {
-            server_addr,
-            local_ip,
-            local_port,
-            software_name,
-            conn: Arc::new(socket),
-        })
-    }
-
-    pub fn discover(&mut self) -> Result<(NAT, Host)> {
-        if self.server_addr.is_empty() {
-            self.server_addr = DEFAULT_SERVER_ADDR.to_string();
+        let mut builder = ClientBuilder::new().socket(socket).software(&software_name);
+        if !server_addr.is_empty() {
+            builder = builder.server(&server_addr);
         }
+        let mut client = builder.build()?;
+        client.local_ip = local_ip;
+        Ok(client)
+    }
 
-        let server_udp_addr = self
-            .server_addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address found"))?;
-
-        let socket = UdpSocket::bind(server_udp_addr)?;
-
-        let mut conn = Arc::clone(&self.conn);
+    pub fn with_servers(
+        server_addrs: Vec<String>,
+        local_ip: String,
+        local_port: u16,
+        software_name: String,
+    ) -> std::io::Result<Client> {
+        let server_addr = server_addrs.first().cloned().unwrap_or_default();
+        let mut client = Client::new(server_addr, local_ip, local_port, software_name)?;
+        client.server_addrs = server_addrs;
+        Ok(client)
+    }
 
-        let nat = NAT::NATBlocked;
-        let host = Host {
-            family: 1,
-            ip: String::from("value"),
-            port: 11,
-        };
-        Ok((nat, host))
+    // Servers to query, falling back to DEFAULT_SERVER_ADDR.
+    pub fn servers(&self) -> Vec<String> {
+        if self.server_addrs.is_empty() {
+            vec![DEFAULT_SERVER_ADDR.to_string()]
+        } else {
+            self.server_addrs.clone()
+        }
     }
 }
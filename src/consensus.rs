@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::utils::join_host_port;
+use crate::{AddressFamily, Client, Host, NAT};
+
+// Result of running discovery against a single server.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct ServerResult {
+    pub server: String,
+    pub nat: NAT,
+    pub mapped_addr: Result<Host, String>,
+    pub rtt: Option<Duration>, // smoothed RTT to the server, if it answered
+}
+
+// Combined verdict of several servers.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct ConsensusResult {
+    pub nat: NAT,
+    pub mapped_addr: Option<Host>,
+    pub agreeing: usize, // number of servers that reported `nat`
+    pub results: Vec<ServerResult>,
+}
+
+impl ConsensusResult {
+    // Servers that errored or never answered are left out of the vote, so a
+    // single blocked or misbehaving server can't decide the verdict. Ties go
+    // to the server listed first.
+    pub fn from_results(results: Vec<ServerResult>) -> ConsensusResult {
+        let votes: Vec<&ServerResult> = results
+            .iter()
+            .filter(|r| r.nat != NAT::NATError && r.nat != NAT::NATBlocked)
+            .collect();
+
+        if votes.is_empty() {
+            let all_blocked =
+                !results.is_empty() && results.iter().all(|r| r.nat == NAT::NATBlocked);
+            return ConsensusResult {
+                nat: if all_blocked {
+                    NAT::NATBlocked
+                } else {
+                    NAT::NATError
+                },
+                mapped_addr: None,
+                agreeing: if all_blocked { results.len() } else { 0 },
+                results,
+            };
+        }
+
+        let mut counts: HashMap<NAT, usize> = HashMap::new();
+        for r in votes.iter() {
+            *counts.entry(r.nat.canonical()).or_insert(0) += 1;
+        }
+        let mut nat = votes[0].nat.canonical();
+        for r in votes.iter() {
+            if counts[&r.nat.canonical()] > counts[&nat] {
+                nat = r.nat.canonical();
+            }
+        }
+
+        // Each server is queried from its own socket, so only the mapped IP
+        // is comparable between servers.
+        let mut ip_counts: HashMap<IpAddr, usize> = HashMap::new();
+        let mapped: Vec<&Host> = votes
+            .iter()
+            .filter(|r| r.nat.canonical() == nat)
+            .filter_map(|r| r.mapped_addr.as_ref().ok())
+            .collect();
+        for h in mapped.iter() {
+            *ip_counts.entry(h.ip()).or_insert(0) += 1;
+        }
+        let mut mapped_addr: Option<&Host> = None;
+        for h in mapped.iter() {
+            if mapped_addr.is_none_or(|m| ip_counts[&h.ip()] > ip_counts[&m.ip()]) {
+                mapped_addr = Some(h);
+            }
+        }
+
+        ConsensusResult {
+            nat,
+            mapped_addr: mapped_addr.cloned(),
+            agreeing: counts[&nat],
+            results,
+        }
+    }
+}
+
+impl Client {
+    // Runs discovery against every configured server concurrently, each from
+    // its own socket, and combines the verdicts.
+    pub fn discover_consensus(&self) -> ConsensusResult {
+        let servers = self.servers();
+        let results = thread::scope(|s| {
+            let handles: Vec<_> = servers
+                .iter()
+                .map(|server| s.spawn(move || self.discover_server(server)))
+                .collect();
+            handles
+                .into_iter()
+                .zip(servers.iter())
+                .map(|(h, server)| {
+                    h.join().unwrap_or_else(|_| ServerResult {
+                        server: server.clone(),
+                        nat: NAT::NATError,
+                        mapped_addr: Err("Discovery panicked".to_string()),
+                        rtt: None,
+                    })
+                })
+                .collect()
+        });
+        let consensus = ConsensusResult::from_results(results);
+        // Successes and timeouts are booked per request, only the verdicts
+        // are left.
+        self.record_wrong_responses(&consensus);
+        consensus
+    }
+
+    fn discover_server(&self, server: &str) -> ServerResult {
+        let result = |nat, mapped_addr, rtt| ServerResult {
+            server: server.to_string(),
+            nat,
+            mapped_addr,
+            rtt,
+        };
+
+        let addr = match self.resolve_server(server) {
+            Ok(addr) => addr,
+            Err(e) => return result(NAT::NATError, Err(e), None),
+        };
+        let conn = match UdpSocket::bind(join_host_port(&self.local_ip, "0")) {
+            Ok(conn) => conn,
+            Err(e) => return result(NAT::NATError, Err(e.to_string()), None),
+        };
+
+        let discovery = self.discover_with(&conn, addr);
+        let rtt = self.rtt_estimate(&addr).and_then(|e| e.srtt);
+        result(discovery.nat, discovery.mapped_addr, rtt)
+    }
+
+    // Resolves a server, preferring the configured address family, else
+    // the family of the local IP.
+    pub fn resolve_server(&self, server: &str) -> Result<SocketAddr, String> {
+        let want_v6 = match self.family {
+            Some(family) => family == AddressFamily::V6,
+            None => self.local_ip.contains(':'),
+        };
+        let addrs: Vec<SocketAddr> = server
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .collect();
+        let addr = addrs
+            .iter()
+            .find(|a| a.is_ipv6() == want_v6)
+            .or(addrs.first())
+            .copied()
+            .ok_or_else(|| format!("No address found for {}", server))?;
+        // Lets exchanges with `addr` be booked under the server's name.
+        if self.health.is_some() {
+            if let Ok(mut names) = self.server_names.lock() {
+                names.insert(addr, server.to_string());
+            }
+        }
+        Ok(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(ip: &str, port: u16) -> Host {
+        Host::from(SocketAddr::new(ip.parse().unwrap(), port))
+    }
+
+    fn result(server: &str, nat: NAT, mapped: Result<Host, String>) -> ServerResult {
+        ServerResult {
+            server: server.to_string(),
+            nat,
+            mapped_addr: mapped,
+            rtt: None,
+        }
+    }
+
+    #[test]
+    fn majority_test() {
+        let consensus = ConsensusResult::from_results(vec![
+            result("a", NAT::NATRestricted, Ok(host("1.2.3.4", 1000))),
+            result("b", NAT::NATFull, Ok(host("5.6.7.8", 1001))),
+            result("c", NAT::NATFull, Ok(host("5.6.7.8", 1002))),
+        ]);
+        assert_eq!(consensus.nat, NAT::NATFull);
+        assert_eq!(consensus.agreeing, 2);
+        assert_eq!(consensus.mapped_addr.unwrap().ip().to_string(), "5.6.7.8");
+        assert_eq!(consensus.results.len(), 3);
+    }
+
+    #[test]
+    fn failover_test() {
+        let consensus = ConsensusResult::from_results(vec![
+            result("a", NAT::NATError, Err("Server error".to_string())),
+            result("b", NAT::NATBlocked, Err("NATBlocked".to_string())),
+            result("c", NAT::NATPortRestricted, Ok(host("1.2.3.4", 1000))),
+        ]);
+        assert_eq!(consensus.nat, NAT::NATPortRestricted);
+        assert_eq!(consensus.agreeing, 1);
+        assert_eq!(consensus.mapped_addr.unwrap().port(), 1000);
+    }
+
+    #[test]
+    fn all_failed_test() {
+        let blocked = ConsensusResult::from_results(vec![
+            result("a", NAT::NATBlocked, Err("NATBlocked".to_string())),
+            result("b", NAT::NATBlocked, Err("NATBlocked".to_string())),
+        ]);
+        assert_eq!(blocked.nat, NAT::NATBlocked);
+
+        let error = ConsensusResult::from_results(vec![
+            result("a", NAT::NATBlocked, Err("NATBlocked".to_string())),
+            result("b", NAT::NATError, Err("Server error".to_string())),
+        ]);
+        assert_eq!(error.nat, NAT::NATError);
+        assert!(error.mapped_addr.is_none());
+    }
+}
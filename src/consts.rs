@@ -1,21 +1,31 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::str::FromStr;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 pub const DEFAULT_SERVER_ADDR: &str = "stun.ekiga.net:3478";
 
 pub const MAGIC_COOKIE: u32 = 0x2112A442;
 pub const FINGERPRINT: u32 = 0x5354554E;
 
 // BehaviorType is NAT behavior type.
-type BehaviorType = i32;
+pub type BehaviorType = i32;
 
 // NATBehavior is NAT behavior type of MappingType and FilteringType.
-#[derive(Debug, PartialEq, Eq, Hash)]
-struct NATBehavior {
-    mapping_type: BehaviorType,
-    filtering_type: BehaviorType,
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NATBehavior {
+    pub mapping_type: BehaviorType,
+    pub filtering_type: BehaviorType,
 }
 
-// NAT types.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+// NAT types. The deprecated spellings compare and hash equal to the
+// variant they stand for.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, Eq)]
 pub enum NAT {
     NATError,
     NATUnknown,
@@ -28,14 +38,18 @@ pub enum NAT {
     SymmetricUDPFirewall,
 
     // Deprecated spellings of these constants
+    #[deprecated(note = "use NAT::NATSymmetric")]
     NATSymetric,
+    #[deprecated(note = "use NAT::SymmetricUDPFirewall")]
     NATSymetricUDPFirewall,
+    #[deprecated(note = "use NAT::SymmetricUDPFirewall")]
     NATSymmetricUDPFirewall,
 }
 
 // Behavior types.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
-enum Behavior {
+pub enum Behavior {
     BehaviorTypeUnknown,
     BehaviorTypeEndpoint,
     BehaviorTypeAddr,
@@ -50,14 +64,11 @@ lazy_static! {
         m.insert(NAT::NATBlocked, "UDP is blocked");
         m.insert(NAT::NATFull, "Full cone NAT");
         m.insert(NAT::NATSymmetric, "Symmetric NAT");
-        m.insert(NAT::NATRestricted, "Restricted NAT");
-        m.insert(NAT::NATPortRestricted, "Port restricted NAT");
+        m.insert(NAT::NATRestricted, "Restricted cone NAT");
+        m.insert(NAT::NATPortRestricted, "Port Restricted cone NAT");
         m.insert(NAT::NATNone, "Not behind a NAT");
         m.insert(NAT::SymmetricUDPFirewall, "Symmetric UDP firewall");
-        // Insert deprecated spellings
-        m.insert(NAT::NATSymetric, "Symmetric NAT");
-        m.insert(NAT::NATSymetricUDPFirewall, "Symmetric UDP firewall");
-        m.insert(NAT::NATSymmetricUDPFirewall, "Symmetric UDP firewall");
+        // Deprecated spellings are looked up by their canonical form
         m
     };
 
@@ -92,6 +103,148 @@ lazy_static! {
     };
 }
 
+#[allow(deprecated)]
+const ALL_NATS: [NAT; 12] = [
+    NAT::NATError,
+    NAT::NATUnknown,
+    NAT::NATNone,
+    NAT::NATBlocked,
+    NAT::NATFull,
+    NAT::NATSymmetric,
+    NAT::NATRestricted,
+    NAT::NATPortRestricted,
+    NAT::SymmetricUDPFirewall,
+    NAT::NATSymetric,
+    NAT::NATSymetricUDPFirewall,
+    NAT::NATSymmetricUDPFirewall,
+];
+
+const ALL_BEHAVIORS: [Behavior; 4] = [
+    Behavior::BehaviorTypeUnknown,
+    Behavior::BehaviorTypeEndpoint,
+    Behavior::BehaviorTypeAddr,
+    Behavior::BehaviorTypeAddrAndPort,
+];
+
+// Lower case without spaces, dashes or underscores, so "Full cone NAT",
+// "full-cone-nat" and "NATFull"-style names compare loosely.
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+impl NAT {
+    // Folds the deprecated spellings into the variant they stand for.
+    #[allow(deprecated)]
+    pub fn canonical(&self) -> NAT {
+        match self {
+            NAT::NATSymetric => NAT::NATSymmetric,
+            NAT::NATSymetricUDPFirewall | NAT::NATSymmetricUDPFirewall => NAT::SymmetricUDPFirewall,
+            nat => *nat,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        NAT_STR.get(&self.canonical()).copied().unwrap_or("Unknown")
+    }
+}
+
+impl PartialEq for NAT {
+    fn eq(&self, other: &NAT) -> bool {
+        mem::discriminant(&self.canonical()) == mem::discriminant(&other.canonical())
+    }
+}
+
+impl Hash for NAT {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(&self.canonical()).hash(state);
+    }
+}
+
+impl fmt::Display for NAT {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+// Accepts the description ("Full cone NAT") or the variant name
+// ("NATFull"), ignoring case and separators. Always yields the canonical
+// variant.
+impl FromStr for NAT {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = normalize(s);
+        ALL_NATS
+            .iter()
+            .find(|nat| {
+                normalize(nat.description()) == name || normalize(&format!("{:?}", nat)) == name
+            })
+            .map(|nat| nat.canonical())
+            .ok_or_else(|| format!("Unknown NAT type: {}", s))
+    }
+}
+
+impl Behavior {
+    pub fn description(&self) -> &'static str {
+        BEHAVIOR_TYPE_STR.get(self).copied().unwrap_or("Unknown")
+    }
+
+    pub fn from_type(t: BehaviorType) -> Behavior {
+        match t {
+            1 => Behavior::BehaviorTypeEndpoint,
+            2 => Behavior::BehaviorTypeAddr,
+            3 => Behavior::BehaviorTypeAddrAndPort,
+            _ => Behavior::BehaviorTypeUnknown,
+        }
+    }
+}
+
+impl fmt::Display for Behavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+// Accepts "EndpointIndependent", "endpoint-independent" or the variant
+// name ("BehaviorTypeEndpoint").
+impl FromStr for Behavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = normalize(s);
+        ALL_BEHAVIORS
+            .iter()
+            .find(|b| normalize(b.description()) == name || normalize(&format!("{:?}", b)) == name)
+            .copied()
+            .ok_or_else(|| format!("Unknown behavior type: {}", s))
+    }
+}
+
+impl NATBehavior {
+    pub fn new(mapping: Behavior, filtering: Behavior) -> NATBehavior {
+        NATBehavior {
+            mapping_type: mapping as BehaviorType,
+            filtering_type: filtering as BehaviorType,
+        }
+    }
+
+    pub fn mapping(&self) -> Behavior {
+        Behavior::from_type(self.mapping_type)
+    }
+
+    pub fn filtering(&self) -> Behavior {
+        Behavior::from_type(self.filtering_type)
+    }
+
+    // The classic RFC 3489 name of this combination, if it has one.
+    pub fn description(&self) -> Option<&'static str> {
+        NAT_NORMAL_TYPE_STR.get(self).copied()
+    }
+}
+
 // Error codes
 pub const ERROR_TRY_ALTERNATE: u16 = 300;
 pub const ERROR_BAD_REQUEST: u16 = 400;
@@ -152,6 +305,7 @@ pub const ATTRIBUTE_CONNECTION_ID: u16 = 0x002a;
 pub const ATTRIBUTE_XOR_MAPPED_ADDRESS_EXP: u16 = 0x8020;
 pub const ATTRIBUTE_SOFTWARE: u16 = 0x8022;
 pub const ATTRIBUTE_ALTERNATE_SERVER: u16 = 0x8023;
+pub const ATTRIBUTE_TRANSACTION_TRANSMIT_COUNTER: u16 = 0x8025;
 pub const ATTRIBUTE_CACHE_TIMEOUT: u16 = 0x8027;
 pub const ATTRIBUTE_FINGERPRINT: u16 = 0x8028;
 pub const ATTRIBUTE_ICE_CONTROLLED: u16 = 0x8029;
@@ -163,6 +317,7 @@ pub const ATTRIBUTE_CISCO_FLOWDATA: u16 = 0xc000;
 
 // Message types
 pub const TYPE_BINDING_REQUEST: u16 = 0x0001;
+pub const TYPE_BINDING_INDICATION: u16 = 0x0011;
 pub const TYPE_BINDING_RESPONSE: u16 = 0x0101;
 pub const TYPE_BINDING_ERROR_RESPONSE: u16 = 0x0111;
 pub const TYPE_SHARED_SECRET_REQUEST: u16 = 0x0002;
@@ -201,22 +356,76 @@ mod tests {
     use super::*;
 
     #[test]
-    fn it_works() {
-        println!("{:?}", *NAT_STR.get(&NAT::NATFull).unwrap());
-        println!(
-            "{:?}",
-            *BEHAVIOR_TYPE_STR
-                .get(&Behavior::BehaviorTypeEndpoint)
-                .unwrap()
+    fn description_test() {
+        assert_eq!(NAT::NATFull.description(), "Full cone NAT");
+        assert_eq!(Behavior::BehaviorTypeAddr.description(), "AddressDependent");
+        let behavior = NATBehavior::new(
+            Behavior::BehaviorTypeEndpoint,
+            Behavior::BehaviorTypeAddrAndPort,
+        );
+        assert_eq!(behavior.mapping(), Behavior::BehaviorTypeEndpoint);
+        assert_eq!(behavior.filtering(), Behavior::BehaviorTypeAddrAndPort);
+        assert_eq!(behavior.description(), Some("Port Restricted cone NAT"));
+        assert_eq!(
+            NATBehavior::new(Behavior::BehaviorTypeAddr, Behavior::BehaviorTypeAddr).description(),
+            None
+        );
+    }
+
+    #[test]
+    fn nat_round_trip_test() {
+        for nat in ALL_NATS {
+            assert_eq!(nat.to_string().parse::<NAT>(), Ok(nat.canonical()));
+            assert_eq!(format!("{:?}", nat).parse::<NAT>(), Ok(nat.canonical()));
+        }
+        assert_eq!(
+            NAT::NATPortRestricted.to_string(),
+            "Port Restricted cone NAT"
+        );
+        assert_eq!(
+            NAT::NATPortRestricted.description(),
+            NATBehavior::new(
+                Behavior::BehaviorTypeEndpoint,
+                Behavior::BehaviorTypeAddrAndPort
+            )
+            .description()
+            .unwrap()
+        );
+        assert_eq!("full-cone nat".parse::<NAT>(), Ok(NAT::NATFull));
+        assert_eq!(
+            "NATSymetricUDPFirewall".parse::<NAT>(),
+            Ok(NAT::SymmetricUDPFirewall)
+        );
+        assert!("Carrier grade NAT".parse::<NAT>().is_err());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_nat_test() {
+        use std::collections::HashSet;
+
+        assert_eq!(NAT::NATSymetric, NAT::NATSymmetric);
+        assert_eq!(NAT::NATSymetricUDPFirewall, NAT::SymmetricUDPFirewall);
+        assert_eq!(NAT::NATSymmetricUDPFirewall, NAT::SymmetricUDPFirewall);
+        assert_ne!(NAT::NATSymetric, NAT::SymmetricUDPFirewall);
+        assert_eq!(NAT::NATSymetric.to_string(), "Symmetric NAT");
+        let nats: HashSet<NAT> = ALL_NATS.into_iter().collect();
+        assert_eq!(nats.len(), 9);
+        assert!(nats.contains(&NAT::NATSymetric));
+    }
+
+    #[test]
+    fn behavior_round_trip_test() {
+        for behavior in ALL_BEHAVIORS {
+            assert_eq!(behavior.to_string().parse::<Behavior>(), Ok(behavior));
+        }
+        assert_eq!(
+            "address-and-port-dependent".parse::<Behavior>(),
+            Ok(Behavior::BehaviorTypeAddrAndPort)
         );
-        println!(
-            "{:?}",
-            *NAT_NORMAL_TYPE_STR
-                .get(&NATBehavior {
-                    mapping_type: Behavior::BehaviorTypeEndpoint as i32,
-                    filtering_type: Behavior::BehaviorTypeEndpoint as i32,
-                })
-                .unwrap()
+        assert_eq!(
+            "BehaviorTypeEndpoint".parse::<Behavior>(),
+            Ok(Behavior::BehaviorTypeEndpoint)
         );
     }
 }
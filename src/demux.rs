@@ -0,0 +1,224 @@
+use std::net::SocketAddr;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{DecodeMode, Packet, MAGIC_COOKIE};
+
+// Multiplexing of STUN, DTLS, RTP and TURN ChannelData on one port
+// (RFC 7983 section 7), by the first byte of the datagram:
+//
+//                  +----------------+
+//                  |        [0..3] -+--> STUN
+//                  |                |
+//                  |      [16..19] -+--> ZRTP
+//                  |                |
+//                  |      [20..63] -+--> DTLS
+//                  |                |
+//                  |      [64..79] -+--> TURN Channel
+//                  |                |
+//                  |    [128..191] -+--> RTP/RTCP
+//                  +----------------+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DatagramClass {
+    Stun,
+    Zrtp,
+    Dtls,
+    ChannelData,
+    Rtp, // RTP and RTCP
+    Other,
+}
+
+// RFC 5389 messages carry the magic cookie. RFC 3489 messages don't, their
+// header length has to match the datagram instead.
+pub fn classify(data: &[u8]) -> DatagramClass {
+    let first = match data.first() {
+        Some(b) => *b,
+        None => return DatagramClass::Other,
+    };
+    match first {
+        0..=3 => {
+            if data.len() < 20 {
+                return DatagramClass::Other;
+            }
+            let cookie = BigEndian::read_u32(&data[4..8]);
+            let length = BigEndian::read_u16(&data[2..4]) as usize;
+            if cookie == MAGIC_COOKIE || length == data.len() - 20 {
+                DatagramClass::Stun
+            } else {
+                DatagramClass::Other
+            }
+        }
+        16..=19 => DatagramClass::Zrtp,
+        20..=63 => DatagramClass::Dtls,
+        64..=79 if data.len() >= 4 => DatagramClass::ChannelData,
+        128..=191 => DatagramClass::Rtp,
+        _ => DatagramClass::Other,
+    }
+}
+
+type Handler<'a> = Box<dyn FnMut(&[u8], SocketAddr) + 'a>;
+type StunHandler<'a> = Box<dyn FnMut(Packet, SocketAddr) + 'a>;
+type ChannelDataHandler<'a> = Box<dyn FnMut(u16, &[u8], SocketAddr) + 'a>;
+
+// Routes every datagram to the handler of its class. Only datagrams
+// classified as STUN reach the Packet parser; those it rejects go to the
+// `other` handler. Classes without a handler are dropped.
+#[derive(Default)]
+pub struct Demux<'a> {
+    stun: Option<StunHandler<'a>>,
+    dtls: Option<Handler<'a>>,
+    rtp: Option<Handler<'a>>,
+    channel_data: Option<ChannelDataHandler<'a>>,
+    other: Option<Handler<'a>>,
+}
+
+impl<'a> Demux<'a> {
+    pub fn new() -> Demux<'a> {
+        Demux::default()
+    }
+
+    pub fn on_stun<F: FnMut(Packet, SocketAddr) + 'a>(mut self, f: F) -> Self {
+        self.stun = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_dtls<F: FnMut(&[u8], SocketAddr) + 'a>(mut self, f: F) -> Self {
+        self.dtls = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_rtp<F: FnMut(&[u8], SocketAddr) + 'a>(mut self, f: F) -> Self {
+        self.rtp = Some(Box::new(f));
+        self
+    }
+
+    // Called with the channel number and the application data.
+    pub fn on_channel_data<F: FnMut(u16, &[u8], SocketAddr) + 'a>(mut self, f: F) -> Self {
+        self.channel_data = Some(Box::new(f));
+        self
+    }
+
+    // ZRTP and anything unrecognised.
+    pub fn on_other<F: FnMut(&[u8], SocketAddr) + 'a>(mut self, f: F) -> Self {
+        self.other = Some(Box::new(f));
+        self
+    }
+
+    pub fn dispatch(&mut self, data: &[u8], from: SocketAddr) -> DatagramClass {
+        let class = classify(data);
+        match class {
+            DatagramClass::Stun => match Packet::decode(data, DecodeMode::Lenient) {
+                Ok(pkt) => {
+                    if let Some(f) = self.stun.as_mut() {
+                        f(pkt, from);
+                    }
+                }
+                Err(_) => {
+                    self.dispatch_other(data, from);
+                    return DatagramClass::Other;
+                }
+            },
+            DatagramClass::Dtls => {
+                if let Some(f) = self.dtls.as_mut() {
+                    f(data, from);
+                }
+            }
+            DatagramClass::Rtp => {
+                if let Some(f) = self.rtp.as_mut() {
+                    f(data, from);
+                }
+            }
+            DatagramClass::ChannelData => {
+                // RFC 8656 section 12.4: channel number, length, data.
+                let channel = BigEndian::read_u16(&data[..2]);
+                let length = BigEndian::read_u16(&data[2..4]) as usize;
+                match data.get(4..4 + length) {
+                    Some(payload) => {
+                        if let Some(f) = self.channel_data.as_mut() {
+                            f(channel, payload, from);
+                        }
+                    }
+                    None => {
+                        self.dispatch_other(data, from);
+                        return DatagramClass::Other;
+                    }
+                }
+            }
+            DatagramClass::Zrtp | DatagramClass::Other => self.dispatch_other(data, from),
+        }
+        class
+    }
+
+    fn dispatch_other(&mut self, data: &[u8], from: SocketAddr) {
+        if let Some(f) = self.other.as_mut() {
+            f(data, from);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Attribute, TYPE_BINDING_REQUEST};
+
+    #[test]
+    fn classify_test() {
+        let mut pkt = Packet::new();
+        pkt.types = TYPE_BINDING_REQUEST;
+        pkt.add_attribute(Attribute::new_software_attribute("stun"));
+        let stun = pkt.bytes();
+        assert_eq!(classify(&stun), DatagramClass::Stun);
+
+        // RFC 3489, no cookie but a consistent length.
+        let mut classic = stun.clone();
+        classic[4..8].copy_from_slice(&[9, 9, 9, 9]);
+        assert_eq!(classify(&classic), DatagramClass::Stun);
+        classic.push(0);
+        assert_eq!(classify(&classic), DatagramClass::Other);
+
+        assert_eq!(classify(&[22, 0xfe, 0xfd]), DatagramClass::Dtls);
+        assert_eq!(classify(&[0x40, 0x00, 0, 0]), DatagramClass::ChannelData);
+        assert_eq!(classify(&[0x80, 0x60, 0, 1]), DatagramClass::Rtp);
+        assert_eq!(classify(&[0x10]), DatagramClass::Zrtp);
+        assert_eq!(classify(&[0xff]), DatagramClass::Other);
+        assert_eq!(classify(&[]), DatagramClass::Other);
+    }
+
+    #[test]
+    fn dispatch_test() {
+        let from: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let mut stun = Vec::new();
+        let mut rtp = 0;
+        let mut channels = Vec::new();
+        let mut other = 0;
+        {
+            let mut demux = Demux::new()
+                .on_stun(|pkt, _| stun.push(pkt.trans_id))
+                .on_rtp(|_, _| rtp += 1)
+                .on_channel_data(|c, d, _| channels.push((c, d.to_vec())))
+                .on_other(|_, _| other += 1);
+
+            let pkt = Packet::new();
+            assert_eq!(demux.dispatch(&pkt.bytes(), from), DatagramClass::Stun);
+            assert_eq!(
+                demux.dispatch(&[0x80, 0x60, 0, 1], from),
+                DatagramClass::Rtp
+            );
+            assert_eq!(
+                demux.dispatch(&[0x40, 0x01, 0, 2, 7, 8], from),
+                DatagramClass::ChannelData
+            );
+            // Truncated ChannelData and unhandled DTLS.
+            assert_eq!(
+                demux.dispatch(&[0x40, 0x01, 0, 9], from),
+                DatagramClass::Other
+            );
+            assert_eq!(demux.dispatch(&[22, 0xfe], from), DatagramClass::Dtls);
+        }
+        assert_eq!(stun.len(), 1);
+        assert_eq!(rtp, 1);
+        assert_eq!(channels, vec![(0x4001, vec![7, 8])]);
+        assert_eq!(other, 1);
+    }
+}
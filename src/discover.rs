@@ -1,6 +1,11 @@
-use crate::Host;
+use crate::report::DiscoveryReport;
+use crate::utils::to_hex;
+use crate::{Host, Response, Transport};
 use std::net::SocketAddr;
-use std::net::UdpSocket;
+use tracing::{debug, debug_span, info, info_span, warn};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 // Follow RFC 3489 and RFC 5389.
 // Figure 2: Flow for type discovery process (from RFC 3489).
@@ -49,134 +54,547 @@ use std::net::UdpSocket;
 use super::Client;
 use super::NAT;
 
+// Outcome of Client::discover.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveryResult {
+    pub server: SocketAddr,
+    pub nat: NAT,
+    pub mapped_addr: Result<Host, String>,
+    pub report: DiscoveryReport, // every test sent along the way
+}
+
 impl Client {
-    pub fn discover(&self, conn: UdpSocket, addr: SocketAddr) -> (NAT, Result<Host, String>) {
-        let resp = match self.test1(&conn, addr.clone()) {
-            Ok(resp) => resp,
-            Err(e) => return (NAT::NATError, Err(e)),
-        };
+    // Runs discovery over the client's own socket against the configured
+    // servers in turn, until one resolves, answers Test I and gives a
+    // verdict other than NATError. Only resolution fails here: with no
+    // server fit, the verdict of the last one comes back in the result,
+    // along with the report of every test sent to it.
+    pub fn discover(&self) -> Result<DiscoveryResult, String> {
+        self.discover_over(self.conn.as_ref())
+    }
 
-        match resp.server_addr.clone() {
-            Some(server_addr) => {
-                if server_addr.ip != addr.ip().to_string() || server_addr.port != addr.port() {
-                    return (
-                        NAT::NATError,
-                        Err("Server error: response IP/port".to_string()),
-                    );
+    pub(crate) fn discover_over(&self, conn: &dyn Transport) -> Result<DiscoveryResult, String> {
+        let mut last = Err("No server configured".to_string());
+        for server in self.servers() {
+            let addr = match self.resolve_server(&server) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    warn!(server = %server, error = %e, "can't resolve server");
+                    if last.is_err() {
+                        last = Err(e);
+                    }
+                    continue;
                 }
+            };
+            let result = self.discover_with(conn, addr);
+            // Like discover_consensus, neither a silent nor a misbehaving
+            // server has a say.
+            if result.nat != NAT::NATBlocked && result.nat != NAT::NATError {
+                return Ok(result);
             }
-            None => return (NAT::NATBlocked, Err("NATBlocked".to_string())),
+            debug!(server = %server, nat = ?result.nat, "trying the next server");
+            last = Ok(result);
         }
+        last
+    }
 
-        let changed_addr = resp.changed_addr;
-        let identical = resp.identical;
+    // Runs discovery against `addr` over `conn`, recording every test sent
+    // along the way.
+    pub(crate) fn discover_with(&self, conn: &dyn Transport, addr: SocketAddr) -> DiscoveryResult {
+        let span = info_span!("discover", server = %addr);
+        let _enter = span.enter();
+        let mut report = DiscoveryReport::new();
+        let (nat, host) = self.discover_steps(conn, addr, &mut report);
+        report.finish(nat, host);
+        DiscoveryResult {
+            server: addr,
+            nat: report.nat,
+            mapped_addr: report.result(),
+            report,
+        }
+    }
 
-        let mapped_addr = match resp.mapped_addr {
-            Some(m) => m,
-            None => return (NAT::NATError, Err("NAT::NATError".to_string())),
+    fn run_test(
+        &self,
+        report: &mut DiscoveryReport,
+        name: &str,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+        change_ip: bool,
+        change_port: bool,
+    ) -> Result<Response, String> {
+        let span = debug_span!("test", name, server = %addr, change_ip, change_port);
+        let _enter = span.enter();
+        let result = report.run(name, addr, change_ip, change_port, || {
+            self.send_bind_req(conn, addr, change_ip, change_port)
+        });
+        let rtt = report.steps.last().and_then(|s| s.rtt);
+        match &result {
+            Ok(resp) => debug!(
+                trans_id = %to_hex(&resp.packet.trans_id),
+                from = ?resp.server_addr.as_ref().map(|h| h.string()),
+                mapped = ?resp.mapped_addr.as_ref().map(|h| h.string()),
+                changed = ?resp.changed_addr.as_ref().or(resp.other_addr.as_ref()).map(|h| h.string()),
+                identical = resp.identical,
+                ?rtt,
+                "response received"
+            ),
+            Err(e) => debug!(error = %e, "no response"),
+        }
+        result
+    }
+
+    fn discover_steps(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+        report: &mut DiscoveryReport,
+    ) -> (NAT, Result<Host, String>) {
+        let resp = match self.run_test(report, "Test I", conn, addr, false, false) {
+            Ok(resp) => resp,
+            Err(_) => {
+                return verdict(
+                    NAT::NATBlocked,
+                    Err("NATBlocked".to_string()),
+                    "Test I: no response",
+                )
+            }
         };
 
-        let change = match changed_addr {
-            Some(addr) => addr,
-            None => match resp.other_addr {
-                Some(other_addr) => other_addr,
-                None => {
-                    return (
-                        NAT::NATError,
-                        Err("Server error: no changed address".to_string()),
-                    )
-                }
-            },
+        if let Some(server_addr) = resp.server_addr {
+            if server_addr.addr != addr {
+                return verdict(
+                    NAT::NATError,
+                    Err("Server error: response IP/port".to_string()),
+                    "Test I: response from unexpected address",
+                );
+            }
+        }
+
+        let mapped_addr = match resp.mapped_addr {
+            Some(m) => m,
+            None => {
+                return verdict(
+                    NAT::NATError,
+                    Err("Server error: no mapped address".to_string()),
+                    "Test I: no mapped address",
+                )
+            }
         };
 
-        let resp = self.test2(&conn, addr);
-        let r = match resp.clone() {
-            Ok(r) => r,
-            Err(e) => return (NAT::NATError, Err(e)),
+        let change = match resp.changed_addr.or(resp.other_addr) {
+            Some(change) => SocketAddr::from(change),
+            None => {
+                return verdict(
+                    NAT::NATError,
+                    Err("Server error: no changed address".to_string()),
+                    "Test I: no changed address",
+                )
+            }
         };
 
-        match r.server_addr {
-            Some(server) => {
-                if server.ip == addr.ip().to_string() || server.port == addr.port() {
-                    return (
+        // Test II asks for the response from the other IP and port. No
+        // response is an answer in itself, handled below.
+        let resp2 = self.run_test(report, "Test II", conn, addr, true, true);
+        if let Ok(r) = &resp2 {
+            if let Some(server) = r.server_addr {
+                if server.ip() == addr.ip() || server.port() == addr.port() {
+                    return verdict(
                         NAT::NATError,
-                        Err("Server error: no changed address".to_string()),
+                        Err("Server error: response IP/port".to_string()),
+                        "Test II: response from unchanged address",
                     );
                 }
             }
-            None => {
-                return (
-                    NAT::NATError,
-                    Err("Server error: response IP/port".to_string()),
-                )
-            }
         }
 
-        if identical {
-            if let Err(_) = resp {
-                return (NAT::SymmetricUDPFirewall, Ok(mapped_addr));
+        if resp.identical {
+            if resp2.is_err() {
+                return verdict(
+                    NAT::SymmetricUDPFirewall,
+                    Ok(mapped_addr),
+                    "Mapped address is local, Test II unanswered",
+                );
             }
-            return (NAT::NATNone, Ok(mapped_addr));
+            return verdict(
+                NAT::NATNone,
+                Ok(mapped_addr),
+                "Mapped address is local, Test II answered",
+            );
         }
 
-        if let Err(_) = resp {
-            return (NAT::NATFull, Ok(mapped_addr));
+        if resp2.is_ok() {
+            return verdict(NAT::NATFull, Ok(mapped_addr), "Test II answered");
         }
-        let addr = change.string().parse::<SocketAddr>().unwrap();
 
-        let resp = self.test1(&conn, addr.clone());
-
-        if let Err(_) = resp {}
-
-        let r = match resp {
+        // Test I again, to the changed address.
+        let r = match self.run_test(report, "Test I", conn, change, false, false) {
             Ok(r) => r,
-            Err(_) => return (NAT::NATUnknown, Ok(mapped_addr)),
+            Err(_) => {
+                return verdict(
+                    NAT::NATUnknown,
+                    Ok(mapped_addr),
+                    "Test I to changed address: no response",
+                )
+            }
         };
 
+        if let Some(server_addr) = r.server_addr {
+            if server_addr.addr != change {
+                return verdict(
+                    NAT::NATError,
+                    Err("Server error: response IP/port".to_string()),
+                    "Test I to changed address: response from unexpected address",
+                );
+            }
+        }
+
         let m_addr = match r.mapped_addr {
             Some(m) => m,
             None => {
-                return (
+                return verdict(
                     NAT::NATError,
-                    Err("Server error: no changed address".to_string()),
+                    Err("Server error: no mapped address".to_string()),
+                    "Test I to changed address: no mapped address",
                 )
             }
         };
 
-        if m_addr.ip != addr.ip().to_string() || m_addr.port != addr.port() {
-            return (
-                NAT::NATError,
-                Err("Server error: response IP/port".to_string()),
+        if m_addr != mapped_addr {
+            return verdict(
+                NAT::NATSymmetric,
+                Ok(mapped_addr),
+                "Mapped address differs between servers",
             );
         }
 
-        if mapped_addr.ip == m_addr.ip && mapped_addr.port == mapped_addr.port {
-            let resp = self.test3(&conn, addr.clone());
+        // Test III asks for the response from the other port only.
+        let r = match self.run_test(report, "Test III", conn, addr, false, true) {
+            Ok(r) => r,
+            Err(_) => {
+                return verdict(
+                    NAT::NATPortRestricted,
+                    Ok(mapped_addr),
+                    "Test III unanswered",
+                )
+            }
+        };
 
-            let r = match resp {
-                Ok(r) => r,
-                Err(_) => {
-                    return (
-                        NAT::NATError,
-                        Err("Server error: response IP/port".to_string()),
-                    )
-                }
-            };
+        if let Some(server_addr) = r.server_addr {
+            if server_addr.ip() != addr.ip() || server_addr.port() == addr.port() {
+                return verdict(
+                    NAT::NATError,
+                    Err("Server error: response IP/port".to_string()),
+                    "Test III: response from unexpected address",
+                );
+            }
+        }
 
-            let s_addr = match r.server_addr {
-                Some(s) => s,
-                None => {
-                    return (NAT::NATPortRestricted, Ok(mapped_addr));
-                }
-            };
+        verdict(NAT::NATRestricted, Ok(mapped_addr), "Test III answered")
+    }
+}
+
+// Logs the flowchart branch that led to a verdict and passes it through.
+fn verdict(nat: NAT, host: Result<Host, String>, branch: &str) -> (NAT, Result<Host, String>) {
+    match &host {
+        Ok(h) => info!(nat = ?nat, mapped = %h.string(), branch, "verdict"),
+        Err(e) => warn!(nat = ?nat, error = %e, branch, "verdict"),
+    }
+    (nat, host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{test_client, test_client_builder, NatConfig, Network, SimServer, SimSocket};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Discovery from 192.168.1.10:5000 to a server on the public side.
+    fn discover(network: Network, socket: SimSocket) -> (NAT, Result<Host, String>) {
+        let server = SimServer::new(
+            "198.51.100.1:3478".parse().unwrap(),
+            "198.51.100.2:3479".parse().unwrap(),
+        );
+        network.add_server(server.clone());
+        let client = test_client();
+        let result = client.discover_with(&socket, server.primary);
+        (result.nat, result.mapped_addr)
+    }
+
+    #[test]
+    fn open_internet_test() {
+        let network = Network::new();
+        let socket = network.bind("192.168.1.10:5000".parse().unwrap()).unwrap();
+        let (nat, host) = discover(network, socket);
+        assert_eq!(nat, NAT::NATNone);
+        assert_eq!(host.unwrap().string(), "192.168.1.10:5000");
+    }
+
+    fn discover_behind(nat: NatConfig) -> (NAT, Result<Host, String>) {
+        let network = Network::with_nat(nat);
+        let socket = network
+            .bind_private("192.168.1.10:5000".parse().unwrap())
+            .unwrap();
+        discover(network, socket)
+    }
+
+    #[test]
+    fn nat_verdict_test() {
+        let cases = [
+            (NatConfig::full_cone(), NAT::NATFull),
+            (NatConfig::restricted(), NAT::NATRestricted),
+            (NatConfig::port_restricted(), NAT::NATPortRestricted),
+            (NatConfig::symmetric(), NAT::NATSymmetric),
+        ];
+        for (config, expected) in cases {
+            let (nat, host) = discover_behind(config);
+            assert_eq!(nat, expected);
+            assert_eq!(host.unwrap().ip().to_string(), "203.0.113.1");
+        }
+    }
+
+    #[test]
+    fn blocked_test() {
+        let (nat, host) = discover_behind(NatConfig::blocked());
+        assert_eq!(nat, NAT::NATBlocked);
+        assert_eq!(host, Err("NATBlocked".to_string()));
+    }
+
+    // The loopback server advertises no changed address, discovery stops
+    // after Test I.
+    #[test]
+    fn discover_test() {
+        let server = crate::Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve());
+
+        let client = test_client_builder(Duration::from_secs(2))
+            .server(&server_addr.to_string())
+            .build()
+            .unwrap();
+        let result = client.discover().unwrap();
+        assert_eq!(result.server, server_addr);
+        assert_eq!(result.nat, NAT::NATError);
+        assert_eq!(result.report.steps.len(), 1);
+        assert_eq!(
+            result.report.steps[0].mapped_addr.unwrap().addr,
+            client.conn.local_addr().unwrap()
+        );
+    }
+
+    // Unanswered and unresolvable servers are skipped.
+    #[test]
+    fn discover_fallback_test() {
+        let server = crate::Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve());
+        // Nobody answers there.
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let client = test_client_builder(Duration::from_millis(200))
+            .server("no port")
+            .server(&silent.local_addr().unwrap().to_string())
+            .server(&server_addr.to_string())
+            .build()
+            .unwrap();
+        let result = client.discover().unwrap();
+        assert_eq!(result.server, server_addr);
+        assert_eq!(result.report.steps.len(), 1);
 
-            if s_addr.ip != addr.ip().to_string() || s_addr.port == addr.port() {
-                return (NAT::NATError, Ok(mapped_addr));
+        // The last unanswered server's verdict when none answers.
+        let client = test_client_builder(Duration::from_millis(200))
+            .server("no port")
+            .server(&silent.local_addr().unwrap().to_string())
+            .build()
+            .unwrap();
+        let result = client.discover().unwrap();
+        assert_eq!(result.server, silent.local_addr().unwrap());
+        assert_eq!(result.nat, NAT::NATBlocked);
+
+        let client = test_client_builder(Duration::from_millis(200))
+            .server("no port")
+            .build()
+            .unwrap();
+        assert!(client.discover().is_err());
+    }
+
+    // A first server that answers without a changed address is skipped.
+    #[test]
+    fn discover_misbehaving_server_test() {
+        let network = Network::with_nat(NatConfig::full_cone());
+        let mut bad = SimServer::new(
+            "198.51.100.1:3478".parse().unwrap(),
+            "198.51.100.2:3479".parse().unwrap(),
+        );
+        bad.other_address = false;
+        let good = SimServer::new(
+            "203.0.113.10:3478".parse().unwrap(),
+            "203.0.113.11:3479".parse().unwrap(),
+        );
+        network.add_server(bad.clone());
+        network.add_server(good.clone());
+        let socket = network
+            .bind_private("192.168.1.10:5000".parse().unwrap())
+            .unwrap();
+        let client = test_client_builder(Duration::from_secs(2))
+            .servers(vec![bad.primary.to_string(), good.primary.to_string()])
+            .build()
+            .unwrap();
+        let result = client.discover_over(&socket).unwrap();
+        assert_eq!(result.server, good.primary);
+        assert_eq!(result.nat, NAT::NATFull);
+
+        // Left with the misbehaving server only, its verdict comes back.
+        let client = test_client_builder(Duration::from_secs(2))
+            .server(&bad.primary.to_string())
+            .build()
+            .unwrap();
+        let result = client.discover_over(&socket).unwrap();
+        assert_eq!(result.nat, NAT::NATError);
+        assert_eq!(result.report.steps.len(), 1);
+    }
+
+    // Test II filtered by the NAT, and the server doesn't answer on its
+    // alternate address: nothing tells the NAT types apart.
+    #[test]
+    fn unknown_test() {
+        let network = Network::with_nat(NatConfig::port_restricted());
+        let mut server = SimServer::new(
+            "198.51.100.1:3478".parse().unwrap(),
+            "198.51.100.2:3479".parse().unwrap(),
+        );
+        server.alternate_reachable = false;
+        network.add_server(server.clone());
+        let socket = network
+            .bind_private("192.168.1.10:5000".parse().unwrap())
+            .unwrap();
+        let client = test_client();
+        let report = client.discover_with(&socket, server.primary).report;
+        assert_eq!(report.nat, NAT::NATUnknown);
+        assert_eq!(report.result().unwrap().ip().to_string(), "203.0.113.1");
+        assert_eq!(report.steps.len(), 3);
+        assert_eq!(report.steps[2].server, "198.51.100.2:3479".parse().unwrap());
+    }
+
+    // Collects span names and the `nat` and `branch` fields of verdict
+    // events.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        next_id: Arc<AtomicU64>,
+        spans: Arc<Mutex<Vec<String>>>,
+        verdicts: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    #[derive(Default)]
+    struct Fields {
+        message: String,
+        nat: String,
+        branch: String,
+    }
+
+    impl tracing::field::Visit for Fields {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            if field.name() == "branch" {
+                self.branch = value.to_string();
             }
+        }
 
-            return (NAT::NATRestricted, Ok(mapped_addr));
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            match field.name() {
+                "message" => self.message = format!("{:?}", value),
+                "nat" => self.nat = format!("{:?}", value),
+                _ => {}
+            }
         }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            self.spans
+                .lock()
+                .unwrap()
+                .push(span.metadata().name().to_string());
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            tracing::span::Id::from_u64(id + 1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            if fields.message == "verdict" {
+                self.verdicts
+                    .lock()
+                    .unwrap()
+                    .push((fields.nat, fields.branch));
+            }
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    // Every verdict is logged once, with the branch that led to it.
+    #[test]
+    fn verdict_trace_test() {
+        let cases = [
+            (NatConfig::blocked(), "NATBlocked", "Test I: no response"),
+            (NatConfig::full_cone(), "NATFull", "Test II answered"),
+            (
+                NatConfig::restricted(),
+                "NATRestricted",
+                "Test III answered",
+            ),
+            (
+                NatConfig::port_restricted(),
+                "NATPortRestricted",
+                "Test III unanswered",
+            ),
+            (
+                NatConfig::symmetric(),
+                "NATSymmetric",
+                "Mapped address differs between servers",
+            ),
+            (
+                NatConfig::firewall(),
+                "SymmetricUDPFirewall",
+                "Mapped address is local, Test II unanswered",
+            ),
+        ];
+        for (config, nat, branch) in cases {
+            let recorder = Recorder::default();
+            let (found, _) =
+                tracing::subscriber::with_default(recorder.clone(), || discover_behind(config));
+            assert_eq!(format!("{:?}", found), nat);
+            assert_eq!(
+                *recorder.verdicts.lock().unwrap(),
+                vec![(nat.to_string(), branch.to_string())]
+            );
+            let spans = recorder.spans.lock().unwrap();
+            assert_eq!(spans[0], "discover");
+            assert!(spans[1..].iter().all(|s| s == "test"));
+        }
+    }
 
-        return (NAT::NATSymetric, Ok(mapped_addr));
+    #[test]
+    fn symmetric_udp_firewall_test() {
+        let network = Network::with_nat(NatConfig::firewall());
+        let socket = network
+            .bind_private("192.168.1.10:5000".parse().unwrap())
+            .unwrap();
+        let (nat, host) = discover(network, socket);
+        assert_eq!(nat, NAT::SymmetricUDPFirewall);
+        assert_eq!(host.unwrap().string(), "192.168.1.10:5000");
     }
 }
@@ -0,0 +1,412 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Attribute, Packet, Transport, ATTRIBUTE_ECN_CHECK_STUN};
+
+use super::Client;
+use super::Response;
+
+// The ECN field, the two low bits of the IPv4 TOS / IPv6 Traffic Class
+// (RFC 3168).
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl Ecn {
+    pub fn from_bits(bits: u8) -> Ecn {
+        match bits & 0b11 {
+            0b00 => Ecn::NotEct,
+            0b01 => Ecn::Ect1,
+            0b10 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+
+    pub fn bits(self) -> u8 {
+        match self {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
+// What happened to the ECN marking of a request on the way to the server.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcnVerdict {
+    Intact,
+    Bleached,      // arrived as Not-ECT
+    Remarked(Ecn), // arrived with another codepoint (CE is congestion, not an error)
+    Dropped,       // marked requests are lost, unmarked ones are answered
+    Unsupported,   // the server didn't report the codepoint it saw
+}
+
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                  Unused                                 |ECF|V|
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// ECN-CHECK of RFC 6679 section 7.2.2. Clients send it with V = 0 to ask
+// for the check, servers answer with the codepoint they received and V = 1.
+impl Attribute {
+    pub fn new_ecn_check_attribute(ecn: Option<Ecn>) -> Attribute {
+        let mut value = vec![0u8; 4];
+        if let Some(ecn) = ecn {
+            value[3] = (ecn.bits() << 1) | 0x01;
+        }
+        Attribute::new(ATTRIBUTE_ECN_CHECK_STUN, &value)
+    }
+}
+
+impl Packet {
+    // None without ECN-CHECK or with V = 0.
+    pub fn get_ecn_check(&self) -> Option<Ecn> {
+        let a = self
+            .attributes
+            .iter()
+            .find(|a| a.s_type == ATTRIBUTE_ECN_CHECK_STUN)?;
+        if a.value.len() < 4 {
+            return None;
+        }
+        let value = BigEndian::read_u32(&a.value[..4]);
+        if value & 0x01 == 0 {
+            return None;
+        }
+        Some(Ecn::from_bits((value >> 1) as u8))
+    }
+
+    pub fn has_ecn_check(&self) -> bool {
+        self.attributes
+            .iter()
+            .any(|a| a.s_type == ATTRIBUTE_ECN_CHECK_STUN)
+    }
+}
+
+impl Response {
+    // None if the request wasn't marked.
+    pub fn ecn_verdict(&self) -> Option<EcnVerdict> {
+        let sent = self.ecn_sent?;
+        Some(match self.ecn_received {
+            None => EcnVerdict::Unsupported,
+            Some(received) if received == sent => EcnVerdict::Intact,
+            Some(Ecn::NotEct) => EcnVerdict::Bleached,
+            Some(received) => EcnVerdict::Remarked(received),
+        })
+    }
+}
+
+impl Client {
+    // Sends a Binding request marked with `ecn`. If it gets lost, an
+    // unmarked one tells whether the marking is what gets dropped.
+    //
+    // Both go through a socket of their own on the local address of `conn`,
+    // the codepoint is never set on `conn`, which other threads or a
+    // TransactionManager may be sending on.
+    pub fn check_ecn(
+        &mut self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+        ecn: Ecn,
+    ) -> Result<EcnVerdict, String> {
+        let local = conn.local_addr().map_err(|e| e.to_string())?;
+        let probe = UdpSocket::bind(SocketAddr::new(local.ip(), 0)).map_err(|e| e.to_string())?;
+        let previous = self.ecn.replace(ecn);
+        let marked = self.send_bind_req(&probe, addr, false, false);
+        self.ecn = previous;
+        match marked {
+            Ok(resp) => Ok(resp.ecn_verdict().unwrap_or(EcnVerdict::Unsupported)),
+            Err(e) => match self.send_bind_req(&probe, addr, false, false) {
+                Ok(_) => Ok(EcnVerdict::Dropped),
+                Err(_) => Err(e),
+            },
+        }
+    }
+}
+
+// The ECN field of the datagrams sent on `conn`.
+#[cfg(unix)]
+pub fn ecn(conn: &UdpSocket) -> io::Result<Ecn> {
+    Ok(Ecn::from_bits(tos(conn)? as u8))
+}
+
+// Sets the ECN field of every datagram sent on `conn`. Only the two ECN
+// bits change, the DSCP the application may have set is kept.
+#[cfg(unix)]
+pub fn set_ecn(conn: &UdpSocket, ecn: Ecn) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let value = (tos(conn)? & !0b11) | ecn.bits() as libc::c_int;
+    let (level, name) = tos_option(conn)?;
+    // SAFETY: `value` outlives the call and its size is passed along.
+    let rc = unsafe {
+        libc::setsockopt(
+            conn.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The whole IPv4 TOS / IPv6 Traffic Class byte of `conn`.
+#[cfg(unix)]
+fn tos(conn: &UdpSocket) -> io::Result<libc::c_int> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = tos_option(conn)?;
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `value` and `len` outlive the call, `len` is the size of
+    // `value`.
+    let rc = unsafe {
+        libc::getsockopt(
+            conn.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Some systems answer IP_TOS with a single byte.
+    if len as usize == 1 {
+        value &= 0xff;
+    }
+    Ok(value)
+}
+
+#[cfg(unix)]
+fn tos_option(conn: &UdpSocket) -> io::Result<(libc::c_int, libc::c_int)> {
+    Ok(if conn.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    })
+}
+
+// Asks the kernel to report the TOS / Traffic Class of received datagrams
+// to recv_with_ecn.
+#[cfg(unix)]
+pub fn enable_recv_ecn(conn: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let on: libc::c_int = 1;
+    let (level, name) = if conn.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_RECVTOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS)
+    };
+    // SAFETY: `on` outlives the call and its size is passed along.
+    let rc = unsafe {
+        libc::setsockopt(
+            conn.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// recv_from that also returns the ECN field, when enable_recv_ecn was
+// called on the socket. Honours the socket's read timeout.
+#[cfg(unix)]
+pub fn recv_with_ecn(
+    conn: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<Ecn>)> {
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: every pointer in `msg` points to a live local buffer whose
+    // size is given next to it; the kernel writes at most that much.
+    // Control messages are only read through the CMSG_* macros.
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut name: libc::sockaddr_storage = mem::zeroed();
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = libc::recvmsg(conn.as_raw_fd(), &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ecn = None;
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let c = &*cmsg;
+            let data = libc::CMSG_DATA(cmsg);
+            if c.cmsg_level == libc::IPPROTO_IP
+                && (c.cmsg_type == libc::IP_TOS || c.cmsg_type == libc::IP_RECVTOS)
+            {
+                ecn = Some(Ecn::from_bits(*data));
+            } else if c.cmsg_level == libc::IPPROTO_IPV6 && c.cmsg_type == libc::IPV6_TCLASS {
+                let tclass = (data as *const libc::c_int).read_unaligned();
+                ecn = Some(Ecn::from_bits(tclass as u8));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        let from = match name.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let a = &*(&name as *const libc::sockaddr_storage as *const libc::sockaddr_in);
+                SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)),
+                    u16::from_be(a.sin_port),
+                ))
+            }
+            libc::AF_INET6 => {
+                let a = &*(&name as *const libc::sockaddr_storage as *const libc::sockaddr_in6);
+                SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(a.sin6_addr.s6_addr),
+                    u16::from_be(a.sin6_port),
+                    a.sin6_flowinfo,
+                    a.sin6_scope_id,
+                ))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unknown address family",
+                ))
+            }
+        };
+        Ok((n as usize, from, ecn))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn ecn(_conn: &UdpSocket) -> io::Result<Ecn> {
+    Ok(Ecn::NotEct)
+}
+
+#[cfg(not(unix))]
+pub fn set_ecn(_conn: &UdpSocket, _ecn: Ecn) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ECN marking not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn enable_recv_ecn(_conn: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ECN reporting not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn recv_with_ecn(
+    conn: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<Ecn>)> {
+    let (n, from) = conn.recv_from(buf)?;
+    Ok((n, from, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::test_client;
+    use crate::Server;
+    use std::thread;
+
+    #[test]
+    fn ecn_check_attribute_test() {
+        let mut pkt = Packet::new();
+        pkt.add_attribute(Attribute::new_ecn_check_attribute(None));
+        assert!(pkt.has_ecn_check());
+        assert_eq!(pkt.get_ecn_check(), None);
+
+        let mut pkt = Packet::new();
+        pkt.add_attribute(Attribute::new_ecn_check_attribute(Some(Ecn::Ect1)));
+        assert_eq!(pkt.attributes[0].value, vec![0, 0, 0, 0x03]);
+        assert_eq!(pkt.get_ecn_check(), Some(Ecn::Ect1));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn ecn_loopback_test() {
+        let server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            for _ in 0..2 {
+                server.serve_one().unwrap();
+            }
+        });
+
+        let mut client = test_client();
+        let conn = client.conn.clone();
+        // Loopback leaves the marking alone.
+        assert_eq!(
+            client.check_ecn(&conn, server_addr, Ecn::Ect0).unwrap(),
+            EcnVerdict::Intact
+        );
+        assert_eq!(ecn(&conn).unwrap(), Ecn::NotEct);
+        let resp = client
+            .send_bind_req(&conn, server_addr, false, false)
+            .unwrap();
+        assert_eq!(resp.ecn_verdict(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn set_ecn_keeps_dscp_test() {
+        use std::os::unix::io::AsRawFd;
+
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // DSCP EF (46) set by the application.
+        let ef: libc::c_int = 46 << 2;
+        // SAFETY: `ef` outlives the call and its size is passed along.
+        let rc = unsafe {
+            libc::setsockopt(
+                conn.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_TOS,
+                &ef as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(rc, 0);
+
+        set_ecn(&conn, Ecn::Ect0).unwrap();
+        assert_eq!(ecn(&conn).unwrap(), Ecn::Ect0);
+        assert_eq!(tos(&conn).unwrap(), ef | 0b10);
+        set_ecn(&conn, Ecn::NotEct).unwrap();
+        assert_eq!(tos(&conn).unwrap(), ef);
+    }
+}
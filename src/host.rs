@@ -1,40 +1,111 @@
-use std::net::ToSocketAddrs;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
 
-use crate::{utils::join_host_port, ATTRIBUTE_FAMILY_IPV4, ATTRIBUTE_FAMILY_IPV6};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+use crate::{ATTRIBUTE_FAMILY_IPV4, ATTRIBUTE_FAMILY_IPV6};
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Host {
-    pub family: u16,
-    pub ip: String,
-    pub port: u16,
+    pub addr: SocketAddr,
 }
 
 impl Host {
-    pub fn new(s: &str) -> Result<Host, std::io::Error> {
-        s.to_socket_addrs()
-            .map_err(|e| e)?
+    // Resolves `s` ("host:port"), taking the first address.
+    pub fn new(s: &str) -> Result<Host, io::Error> {
+        s.to_socket_addrs()?
             .next()
-            .map(|addr| {
-                let family = if addr.ip().is_ipv4() {
-                    ATTRIBUTE_FAMILY_IPV4
-                } else {
-                    ATTRIBUTE_FAMILY_IPV6
-                };
-                Host {
-                    family,
-                    ip: addr.ip().to_string(),
-                    port: addr.port(),
-                }
-            })
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No address found"))
-        // 将 None 转换为 Err
+            .map(Host::from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address found"))
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.addr.ip()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    // STUN address family code.
+    pub fn family(&self) -> u16 {
+        family(&self.addr.ip())
     }
 
     pub fn transport_addr(&self) -> String {
-        join_host_port(&self.ip, &self.port.to_string())
+        self.addr.to_string()
     }
 
     pub fn string(&self) -> String {
-        Host::transport_addr(&self)
+        self.transport_addr()
+    }
+}
+
+// STUN address family code of `ip`.
+pub fn family(ip: &IpAddr) -> u16 {
+    match ip {
+        IpAddr::V4(_) => ATTRIBUTE_FAMILY_IPV4,
+        IpAddr::V6(_) => ATTRIBUTE_FAMILY_IPV6,
+    }
+}
+
+// Address length of a STUN address family, None for unknown families.
+pub fn family_len(family: u16) -> Option<usize> {
+    match family {
+        ATTRIBUTE_FAMILY_IPV4 => Some(4),
+        ATTRIBUTE_FAMILY_IPV6 => Some(16),
+        _ => None,
+    }
+}
+
+impl From<SocketAddr> for Host {
+    fn from(addr: SocketAddr) -> Host {
+        Host { addr }
+    }
+}
+
+impl From<Host> for SocketAddr {
+    fn from(host: Host) -> SocketAddr {
+        host.addr
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.addr.fmt(f)
+    }
+}
+
+// Parses an "ip:port" literal, without name resolution.
+impl FromStr for Host {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Host, Self::Err> {
+        s.parse::<SocketAddr>().map(Host::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_test() {
+        let host: Host = "[2001:db8::1]:3478".parse().unwrap();
+        assert_eq!(host.family(), ATTRIBUTE_FAMILY_IPV6);
+        assert_eq!(host.port(), 3478);
+        assert_eq!(host.string(), "[2001:db8::1]:3478");
+        assert_eq!(SocketAddr::from(host), host.addr);
+
+        let host = Host::new("127.0.0.1:80").unwrap();
+        assert_eq!(host.family(), ATTRIBUTE_FAMILY_IPV4);
+        assert_eq!(host.ip().to_string(), "127.0.0.1");
+        assert_eq!(family_len(host.family()), Some(4));
+        assert_eq!(family_len(0), None);
+        assert!("stun.example.com:3478".parse::<Host>().is_err());
     }
 }
@@ -0,0 +1,449 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+
+use local_ip_address::list_afinet_netifas;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::Host;
+
+use super::Client;
+
+// Name prefixes of container and VM bridges. Their addresses are private to
+// the host and never have a mapping of their own.
+const VIRTUAL_PREFIXES: [&str; 9] = [
+    "docker", "veth", "br-", "virbr", "vmnet", "vboxnet", "cni", "flannel", "cali",
+];
+
+// IFA_F_TEMPORARY in /proc/net/if_inet6.
+#[cfg(target_os = "linux")]
+const IFA_F_TEMPORARY: u32 = 0x01;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub ip: IpAddr,
+    pub temporary: bool, // RFC 8981 IPv6 temporary address
+}
+
+impl Interface {
+    pub fn is_virtual(&self) -> bool {
+        VIRTUAL_PREFIXES.iter().any(|p| self.name.starts_with(p))
+    }
+}
+
+// What to do with IPv6 temporary (privacy) addresses.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemporaryPolicy {
+    Include,
+    Exclude,
+    // Only temporary addresses on interfaces that have one, so the stable
+    // address isn't exposed to the server.
+    Prefer,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceFilter {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub loopback: bool,
+    pub link_local: bool,
+    pub virtual_bridges: bool, // docker, veth and other bridges
+    pub temporary: TemporaryPolicy,
+}
+
+impl Default for InterfaceFilter {
+    fn default() -> InterfaceFilter {
+        InterfaceFilter {
+            ipv4: true,
+            ipv6: true,
+            loopback: false,
+            link_local: false,
+            virtual_bridges: false,
+            temporary: TemporaryPolicy::Prefer,
+        }
+    }
+}
+
+impl InterfaceFilter {
+    fn allows(&self, i: &Interface) -> bool {
+        let family = if i.ip.is_ipv4() { self.ipv4 } else { self.ipv6 };
+        family
+            && (self.loopback || !i.ip.is_loopback())
+            && (self.link_local || !is_link_local(&i.ip))
+            && (self.virtual_bridges || !i.is_virtual())
+            && !(self.temporary == TemporaryPolicy::Exclude && i.temporary)
+    }
+
+    // Keeps the interfaces the filter allows.
+    pub fn apply(&self, interfaces: Vec<Interface>) -> Vec<Interface> {
+        let mut selected: Vec<Interface> =
+            interfaces.into_iter().filter(|i| self.allows(i)).collect();
+        if self.temporary == TemporaryPolicy::Prefer {
+            let with_temporary: HashSet<String> = selected
+                .iter()
+                .filter(|i| i.temporary)
+                .map(|i| i.name.clone())
+                .collect();
+            selected.retain(|i| {
+                !i.ip.is_ipv6()
+                    || i.temporary
+                    || is_link_local(&i.ip)
+                    || !with_temporary.contains(&i.name)
+            });
+        }
+        selected
+    }
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+// Every local address, unfiltered.
+pub fn all() -> io::Result<Vec<Interface>> {
+    let temporary = temporary_addrs();
+    let interfaces = list_afinet_netifas().map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(interfaces
+        .into_iter()
+        .map(|(name, ip)| Interface {
+            temporary: temporary.contains(&ip),
+            name,
+            ip,
+        })
+        .collect())
+}
+
+pub fn list(filter: &InterfaceFilter) -> io::Result<Vec<Interface>> {
+    Ok(filter.apply(all()?))
+}
+
+// Whether `ip` is assigned to this host.
+pub fn is_local_ip(ip: &IpAddr) -> bool {
+    ip.is_loopback() || all().is_ok_and(|all| all.iter().any(|i| &i.ip == ip))
+}
+
+// Each line: address, ifindex, prefix length, scope, flags, name.
+#[cfg(target_os = "linux")]
+fn parse_if_inet6(content: &str) -> HashSet<IpAddr> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || fields[0].len() != 32 {
+                return None;
+            }
+            let flags = u32::from_str_radix(fields[4], 16).ok()?;
+            if flags & IFA_F_TEMPORARY == 0 {
+                return None;
+            }
+            let mut octets = [0u8; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = u8::from_str_radix(fields[0].get(i * 2..i * 2 + 2)?, 16).ok()?;
+            }
+            Some(IpAddr::from(octets))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn temporary_addrs() -> HashSet<IpAddr> {
+    std::fs::read_to_string("/proc/net/if_inet6")
+        .map(|content| parse_if_inet6(&content))
+        .unwrap_or_default()
+}
+
+// No portable way to tell, every address counts as stable.
+#[cfg(not(target_os = "linux"))]
+fn temporary_addrs() -> HashSet<IpAddr> {
+    HashSet::new()
+}
+
+// The kernel routes by destination, not by source address: a socket bound
+// to an interface's address still leaves through the default route unless
+// it is also bound to the device. Without `bound_to_device` the mapped
+// address may be the default uplink's, or the request dropped by reverse
+// path filtering.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct InterfaceMapping {
+    pub interface: Interface,
+    pub local_addr: Option<SocketAddr>,
+    pub mapped_addr: Result<Host, String>,
+    pub bound_to_device: bool, // SO_BINDTODEVICE / IP_BOUND_IF succeeded
+}
+
+impl Client {
+    // Binds one socket per interface and asks the first server of the
+    // matching address family for its mapped address. Interfaces are
+    // queried concurrently, the results come back in interface order.
+    pub fn interface_mappings(
+        &self,
+        filter: &InterfaceFilter,
+    ) -> io::Result<Vec<InterfaceMapping>> {
+        let interfaces = list(filter)?;
+        Ok(thread::scope(|s| {
+            let handles: Vec<_> = interfaces
+                .iter()
+                .map(|i| s.spawn(move || self.interface_mapping(i)))
+                .collect();
+            handles
+                .into_iter()
+                .zip(interfaces.iter())
+                .map(|(h, i)| {
+                    h.join().unwrap_or_else(|_| InterfaceMapping {
+                        interface: i.clone(),
+                        local_addr: None,
+                        mapped_addr: Err("Binding request panicked".to_string()),
+                        bound_to_device: false,
+                    })
+                })
+                .collect()
+        }))
+    }
+
+    fn interface_mapping(&self, interface: &Interface) -> InterfaceMapping {
+        let mut mapping = InterfaceMapping {
+            interface: interface.clone(),
+            local_addr: None,
+            mapped_addr: Err(String::new()),
+            bound_to_device: false,
+        };
+        let conn = match UdpSocket::bind(SocketAddr::new(interface.ip, 0)) {
+            Ok(conn) => conn,
+            Err(e) => {
+                mapping.mapped_addr = Err(e.to_string());
+                return mapping;
+            }
+        };
+        match bind_to_device(&conn, &interface.name) {
+            Ok(()) => mapping.bound_to_device = true,
+            Err(e) => debug!(
+                interface = %interface.name,
+                error = %e,
+                "can't bind to the device, the request follows the routing table"
+            ),
+        }
+        mapping.local_addr = conn.local_addr().ok();
+        mapping.mapped_addr = self.server_for(&interface.ip).and_then(|addr| {
+            self.send_bind_req(&conn, addr, false, false)?
+                .mapped_addr
+                .ok_or_else(|| "Server error: no mapped address".to_string())
+        });
+        mapping
+    }
+
+    // First server with an address of the same family as `ip`.
+    fn server_for(&self, ip: &IpAddr) -> Result<SocketAddr, String> {
+        self.servers()
+            .iter()
+            .filter_map(|server| server.to_socket_addrs().ok())
+            .flatten()
+            .find(|addr| addr.is_ipv6() == ip.is_ipv6())
+            .ok_or_else(|| format!("No server with an address of the family of {}", ip))
+    }
+}
+
+// Sends the socket's datagrams out of `name` whatever the routing table
+// says. Needs CAP_NET_RAW on Linux before 5.7.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bind_to_device(conn: &UdpSocket, name: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `name` outlives the call and its length is passed along.
+    let rc = unsafe {
+        libc::setsockopt(
+            conn.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn bind_to_device(conn: &UdpSocket, name: &str) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::io::AsRawFd;
+
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `name` is a NUL terminated string that outlives the call.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) } as libc::c_int;
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (level, option) = if conn.local_addr()?.is_ipv4() {
+        (libc::IPPROTO_IP, libc::IP_BOUND_IF)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_BOUND_IF)
+    };
+    // SAFETY: `index` outlives the call and its size is passed along.
+    let rc = unsafe {
+        libc::setsockopt(
+            conn.as_raw_fd(),
+            level,
+            option,
+            &index as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+pub fn bind_to_device(_conn: &UdpSocket, _name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to a device not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::test_client_builder;
+
+    fn interface(name: &str, ip: &str, temporary: bool) -> Interface {
+        Interface {
+            name: name.to_string(),
+            ip: ip.parse().unwrap(),
+            temporary,
+        }
+    }
+
+    fn ips(interfaces: &[Interface]) -> Vec<String> {
+        interfaces.iter().map(|i| i.ip.to_string()).collect()
+    }
+
+    #[test]
+    fn filter_test() {
+        let interfaces = vec![
+            interface("lo", "127.0.0.1", false),
+            interface("eth0", "192.168.1.10", false),
+            interface("eth0", "2001:db8::10", false),
+            interface("eth0", "2001:db8::abcd", true),
+            interface("eth0", "fe80::1", false),
+            interface("eth1", "2001:db8:1::10", false),
+            interface("docker0", "172.17.0.1", false),
+            interface("veth1a2b", "fe80::2", false),
+            interface("eth1", "169.254.3.4", false),
+        ];
+
+        let filter = InterfaceFilter::default();
+        assert_eq!(
+            ips(&filter.apply(interfaces.clone())),
+            ["192.168.1.10", "2001:db8::abcd", "2001:db8:1::10"]
+        );
+
+        let filter = InterfaceFilter {
+            temporary: TemporaryPolicy::Exclude,
+            ipv4: false,
+            ..InterfaceFilter::default()
+        };
+        assert_eq!(
+            ips(&filter.apply(interfaces.clone())),
+            ["2001:db8::10", "2001:db8:1::10"]
+        );
+
+        let filter = InterfaceFilter {
+            temporary: TemporaryPolicy::Include,
+            loopback: true,
+            link_local: true,
+            virtual_bridges: true,
+            ..InterfaceFilter::default()
+        };
+        assert_eq!(filter.apply(interfaces.clone()), interfaces);
+    }
+
+    #[test]
+    fn interface_mappings_test() {
+        let server = crate::Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve());
+
+        // Bound to their device, the other interfaces can't reach the
+        // loopback server.
+        let client = test_client_builder(std::time::Duration::from_millis(500))
+            .server(&server_addr.to_string())
+            .build()
+            .unwrap();
+        let filter = InterfaceFilter {
+            ipv6: false,
+            loopback: true,
+            ..InterfaceFilter::default()
+        };
+        let mappings = client.interface_mappings(&filter).unwrap();
+        let lo = mappings
+            .iter()
+            .find(|m| m.interface.ip.is_loopback())
+            .unwrap();
+        assert_eq!(
+            lo.mapped_addr.as_ref().unwrap().string(),
+            lo.local_addr.unwrap().to_string()
+        );
+        #[cfg(target_os = "linux")]
+        assert!(lo.bound_to_device);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn bind_to_device_test() {
+        use std::os::unix::io::AsRawFd;
+
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        bind_to_device(&conn, "lo").unwrap();
+        let mut name = [0u8; 16];
+        let mut len = name.len() as libc::socklen_t;
+        // SAFETY: `name` and `len` outlive the call, `len` is the size of
+        // `name`.
+        let rc = unsafe {
+            libc::getsockopt(
+                conn.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                name.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(rc, 0);
+        assert_eq!(&name[..2], b"lo");
+
+        assert!(bind_to_device(&conn, "no-such-device0").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_if_inet6_test() {
+        let content = "\
+00000000000000000000000000000001 01 80 10 80       lo
+20010db8000000000000000000000010 02 40 00 00     eth0
+20010db800000000000000000000abcd 02 40 00 01     eth0
+fe800000000000000000000000000001 02 40 20 80     eth0
+";
+        let temporary = parse_if_inet6(content);
+        assert_eq!(temporary.len(), 1);
+        assert!(temporary.contains(&"2001:db8::abcd".parse::<IpAddr>().unwrap()));
+    }
+}
@@ -2,21 +2,53 @@
 extern crate lazy_static;
 
 pub mod attribute;
+pub mod behavior;
+pub mod builder;
 pub mod client;
+pub mod consensus;
 pub mod consts;
+pub mod demux;
 pub mod discover;
+pub mod ecn;
 pub mod host;
+pub mod interfaces;
+pub mod message;
+pub mod mtu;
 pub mod net;
 pub mod packet;
+pub mod packet_ref;
+pub mod report;
 pub mod response;
-pub mod tests;
+pub mod retransmit;
+pub mod secret;
+pub mod server;
+pub mod servers;
+pub mod sim;
+pub mod srv;
+pub mod transaction;
+pub mod transport;
 pub mod utils;
 
 pub use consts::*;
 
 pub use attribute::Attribute;
+pub use builder::{AddressFamily, ClientBuilder};
 pub use client::Client;
-pub use consts::NAT;
+pub use consensus::{ConsensusResult, ServerResult};
+pub use consts::{Behavior, NATBehavior, NAT};
+pub use demux::{DatagramClass, Demux};
+pub use discover::DiscoveryResult;
+pub use ecn::{Ecn, EcnVerdict};
 pub use host::Host;
-pub use packet::Packet;
+pub use interfaces::{Interface, InterfaceFilter, InterfaceMapping, TemporaryPolicy};
+pub use message::{Class, MessageType, Method};
+pub use packet::{DecodeMode, Packet, PacketError, Version};
+pub use packet_ref::{AttributeRef, PacketRef, PacketWriter};
+pub use report::{DiscoveryReport, TestStep};
 pub use response::Response;
+pub use retransmit::{RetransmitPolicy, RttEstimate};
+pub use secret::Credentials;
+pub use server::Server;
+pub use servers::{HealthStore, ServerEntry, ServerList};
+pub use transaction::{Event, TransactionManager};
+pub use transport::Transport;
@@ -0,0 +1,212 @@
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::Packet;
+
+// RFC 5389 section 6: the message type interleaves a 12 bit method with a
+// 2 bit class.
+//
+//  0                 1
+//  2  3  4 5 6 7 8 9 0 1 2 3 4 5
+// +--+--+-+-+-+-+-+-+-+-+-+-+-+-+
+// |M |M |M|M|M|C|M|M|M|C|M|M|M|M|
+// |11|10|9|8|7|1|6|5|4|0|3|2|1|0|
+// +--+--+-+-+-+-+-+-+-+-+-+-+-+-+
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+    Request,
+    Indication,
+    SuccessResponse,
+    ErrorResponse,
+}
+
+impl Class {
+    fn bits(self) -> u16 {
+        match self {
+            Class::Request => 0b00,
+            Class::Indication => 0b01,
+            Class::SuccessResponse => 0b10,
+            Class::ErrorResponse => 0b11,
+        }
+    }
+
+    fn from_bits(bits: u16) -> Class {
+        match bits & 0b11 {
+            0b00 => Class::Request,
+            0b01 => Class::Indication,
+            0b10 => Class::SuccessResponse,
+            _ => Class::ErrorResponse,
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Binding,
+    SharedSecret, // RFC 3489 only
+    Allocate,
+    Refresh,
+    Send,
+    Data,
+    CreatePermission,
+    ChannelBind,
+    Other(u16), // any other 12 bit method
+}
+
+impl Method {
+    pub fn value(self) -> u16 {
+        match self {
+            Method::Binding => 0x001,
+            Method::SharedSecret => 0x002,
+            Method::Allocate => 0x003,
+            Method::Refresh => 0x004,
+            Method::Send => 0x006,
+            Method::Data => 0x007,
+            Method::CreatePermission => 0x008,
+            Method::ChannelBind => 0x009,
+            Method::Other(m) => m & 0x0fff,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Method {
+        match value & 0x0fff {
+            0x001 => Method::Binding,
+            0x002 => Method::SharedSecret,
+            0x003 => Method::Allocate,
+            0x004 => Method::Refresh,
+            0x006 => Method::Send,
+            0x007 => Method::Data,
+            0x008 => Method::CreatePermission,
+            0x009 => Method::ChannelBind,
+            m => Method::Other(m),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageType {
+    pub method: Method,
+    pub class: Class,
+}
+
+impl MessageType {
+    pub fn new(method: Method, class: Class) -> MessageType {
+        MessageType { method, class }
+    }
+
+    pub fn from_u16(types: u16) -> MessageType {
+        let method = (types & 0x000f) | ((types & 0x00e0) >> 1) | ((types & 0x3e00) >> 2);
+        let class = ((types >> 4) & 0b01) | ((types >> 7) & 0b10);
+        MessageType {
+            method: Method::from_value(method),
+            class: Class::from_bits(class),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        let m = self.method.value();
+        let c = self.class.bits();
+        (m & 0x000f)
+            | ((m & 0x0070) << 1)
+            | ((m & 0x0f80) << 2)
+            | ((c & 0b01) << 4)
+            | ((c & 0b10) << 7)
+    }
+}
+
+impl From<u16> for MessageType {
+    fn from(types: u16) -> Self {
+        MessageType::from_u16(types)
+    }
+}
+
+impl From<MessageType> for u16 {
+    fn from(t: MessageType) -> Self {
+        t.to_u16()
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let method = match self.method {
+            Method::Binding => "Binding".to_string(),
+            Method::SharedSecret => "Shared Secret".to_string(),
+            Method::Allocate => "Allocate".to_string(),
+            Method::Refresh => "Refresh".to_string(),
+            Method::Send => "Send".to_string(),
+            Method::Data => "Data".to_string(),
+            Method::CreatePermission => "CreatePermission".to_string(),
+            Method::ChannelBind => "ChannelBind".to_string(),
+            Method::Other(m) => format!("Method {:#05x}", m),
+        };
+        let class = match self.class {
+            Class::Request => "request",
+            Class::Indication => "indication",
+            Class::SuccessResponse => "success response",
+            Class::ErrorResponse => "error response",
+        };
+        write!(f, "{} {}", method, class)
+    }
+}
+
+impl Packet {
+    pub fn message_type(&self) -> MessageType {
+        MessageType::from_u16(self.types)
+    }
+
+    pub fn set_message_type(&mut self, t: MessageType) {
+        self.types = t.to_u16();
+    }
+
+    pub fn method(&self) -> Method {
+        self.message_type().method
+    }
+
+    pub fn class(&self) -> Class {
+        self.message_type().class
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TYPE_ALLOCATE_ERROR_RESPONSE, TYPE_BINDING_REQUEST, TYPE_BINDING_RESPONSE};
+
+    #[test]
+    fn message_type_test() {
+        let t = MessageType::from_u16(TYPE_BINDING_RESPONSE);
+        assert_eq!(t, MessageType::new(Method::Binding, Class::SuccessResponse));
+        assert_eq!(
+            MessageType::from_u16(TYPE_ALLOCATE_ERROR_RESPONSE),
+            MessageType::new(Method::Allocate, Class::ErrorResponse)
+        );
+        assert_eq!(
+            MessageType::new(Method::Binding, Class::Indication).to_u16(),
+            0x0011
+        );
+        assert_eq!(t.to_string(), "Binding success response");
+
+        // Every 14 bit type survives the round trip.
+        for types in 0..0x4000u16 {
+            assert_eq!(MessageType::from_u16(types).to_u16(), types);
+        }
+    }
+
+    #[test]
+    fn packet_message_type_test() {
+        let mut pkt = Packet::new();
+        pkt.types = TYPE_BINDING_REQUEST;
+        assert_eq!(pkt.method(), Method::Binding);
+        assert_eq!(pkt.class(), Class::Request);
+
+        pkt.set_message_type(MessageType::new(Method::Other(0xabc), Class::Indication));
+        assert_eq!(pkt.message_type().method, Method::Other(0xabc));
+        assert_eq!(pkt.class(), Class::Indication);
+    }
+}
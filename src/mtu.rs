@@ -0,0 +1,263 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::interfaces;
+use crate::net::DEFAULT_RECV_BUFFER_SIZE;
+use crate::{
+    Attribute, Packet, Response, RetransmitPolicy, Transport, TYPE_BINDING_ERROR_RESPONSE,
+};
+
+use super::Client;
+
+// Path MTU probing (RFC 5780 section 4.5 / 7.6).
+// Binding requests are padded with a PADDING attribute to a given datagram
+// size and sent with DF set; the largest one that is answered is the largest
+// datagram that gets through the NAT and firewall without fragmentation.
+// Servers that pad their responses to the request's size (RFC 5780 section
+// 7.8) make the probe cover the path back as well; with others only the
+// path to the server is measured.
+
+// Smallest datagram every IPv4 host must accept: 576 minus IPv4/UDP headers.
+pub const MIN_PROBE_SIZE: usize = 548;
+// Largest multiple of 4 within the 65507 byte IPv4 UDP payload limit.
+pub const MAX_PROBE_SIZE: usize = 65504;
+// Probing stops once the search window is narrower than this.
+const PROBE_GRANULARITY: usize = 16;
+// MTU assumed when the interface's can't be read.
+const ETHERNET_MTU: usize = 1500;
+
+// A lost probe is the expected outcome of an oversized one, don't wait out
+// the default ~9.5s schedule for each.
+fn probe_policy(policy: &RetransmitPolicy) -> RetransmitPolicy {
+    RetransmitPolicy {
+        initial_rto: Duration::from_millis(200),
+        rc: 2,
+        rm: 2,
+        total_timeout: Some(Duration::from_secs(1)),
+        ..*policy
+    }
+}
+
+impl Client {
+    // Binding request padded so the whole datagram is `size` bytes,
+    // rounded down to a multiple of 4.
+    pub fn new_padded_bind_req(&self, size: usize) -> Result<Packet, String> {
+        // PADDING costs a 4 byte attribute header on top of the base request.
+        let min = self.new_bind_req(false, false, Vec::new()).bytes().len() + 4;
+        if size < min {
+            return Err(format!("Probe size {} below minimum {}", size, min));
+        }
+        let padding = Attribute::new_padding_attribute((size - min) & !3);
+        Ok(self.new_bind_req(false, false, vec![padding]))
+    }
+
+    pub fn send_padded_bind_req(
+        &self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+        size: usize,
+    ) -> Result<Response, String> {
+        let pkt = self.new_padded_bind_req(size)?;
+        let resp = self.send(pkt, conn, addr).map_err(|e| e.to_string())?;
+        // PADDING is comprehension-required, servers without RFC 5780
+        // support answer 420 (Unknown Attribute).
+        if resp.packet.types == TYPE_BINDING_ERROR_RESPONSE {
+            return Err("Server error: PADDING not supported".to_string());
+        }
+        Ok(resp)
+    }
+
+    // Finds the largest Binding request that gets answered and sizes the
+    // receive buffer to match. Never shrinks the buffer below
+    // DEFAULT_RECV_BUFFER_SIZE.
+    pub fn probe_path_mtu(
+        &mut self,
+        conn: &dyn Transport,
+        addr: SocketAddr,
+    ) -> Result<usize, String> {
+        let saved = (self.recv_buffer_size, self.retransmit_policy);
+        // Padded responses are as big as the probe, leave room for the
+        // biggest one.
+        self.recv_buffer_size = u16::MAX as usize;
+        self.retransmit_policy = probe_policy(&self.retransmit_policy);
+        // Without DF oversized probes are fragmented and still answered, the
+        // interface MTU is then all that bounds the result.
+        let dont_fragment = conn.set_dont_fragment(true);
+        if let Err(e) = &dont_fragment {
+            warn!(error = %e, "can't set DF, fragmented probes may pass");
+        }
+        let result = self.probe_max_size(conn, addr);
+        if dont_fragment.is_ok() {
+            if let Err(e) = conn.set_dont_fragment(false) {
+                warn!(error = %e, "can't clear DF on the socket");
+            }
+        }
+        self.retransmit_policy = saved.1;
+        self.recv_buffer_size = match result {
+            Ok(size) => size.max(DEFAULT_RECV_BUFFER_SIZE),
+            Err(_) => saved.0,
+        };
+        result
+    }
+
+    fn probe_max_size(&self, conn: &dyn Transport, addr: SocketAddr) -> Result<usize, String> {
+        let local = conn.local_addr().map_err(|e| e.to_string())?;
+        let max = probe_ceiling(&local.ip(), addr.is_ipv6());
+        // If the smallest probe fails nothing larger will pass either.
+        let resp = self.send_padded_bind_req(conn, addr, MIN_PROBE_SIZE)?;
+        if resp.packet.bytes().len() < MIN_PROBE_SIZE {
+            warn!(server = %addr, "responses aren't padded, only the path to the server is probed");
+        }
+        if self.send_padded_bind_req(conn, addr, max).is_ok() {
+            return Ok(max);
+        }
+
+        let (mut lo, mut hi) = (MIN_PROBE_SIZE, max);
+        while hi - lo > PROBE_GRANULARITY {
+            let mid = ((lo + hi) / 2) & !3;
+            match self.send_padded_bind_req(conn, addr, mid) {
+                Ok(_) => lo = mid,
+                Err(_) => hi = mid,
+            }
+        }
+        Ok(lo)
+    }
+}
+
+// Largest UDP payload the interface of `local` sends unfragmented. A socket
+// bound to the unspecified address may use any interface, the largest MTU
+// bounds it then.
+fn probe_ceiling(local: &IpAddr, ipv6: bool) -> usize {
+    let interfaces = interfaces::all().unwrap_or_default();
+    let mtu = interfaces
+        .iter()
+        .filter(|i| {
+            if local.is_unspecified() {
+                !i.ip.is_loopback()
+            } else {
+                &i.ip == local
+            }
+        })
+        .filter_map(|i| interface_mtu(&i.name))
+        .max()
+        .unwrap_or(ETHERNET_MTU);
+    let headers = if ipv6 { 40 + 8 } else { 20 + 8 };
+    (mtu.saturating_sub(headers) & !3).clamp(MIN_PROBE_SIZE, MAX_PROBE_SIZE)
+}
+
+#[cfg(target_os = "linux")]
+fn interface_mtu(name: &str) -> Option<usize> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_mtu(_name: &str) -> Option<usize> {
+    None
+}
+
+// Sets DF on every datagram sent on `conn`, so oversized ones fail instead
+// of being fragmented. Clearing it goes back to the kernel default (DF with
+// fallback to fragmentation), not to whatever was set before.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_dont_fragment(conn: &UdpSocket, on: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name, value) = if conn.local_addr()?.is_ipv4() {
+        let value = if on {
+            libc::IP_PMTUDISC_DO
+        } else {
+            libc::IP_PMTUDISC_WANT
+        };
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, value)
+    } else {
+        let value = if on {
+            libc::IPV6_PMTUDISC_DO
+        } else {
+            libc::IPV6_PMTUDISC_WANT
+        };
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, value)
+    };
+    // SAFETY: `value` outlives the call and its size is passed along.
+    let rc = unsafe {
+        libc::setsockopt(
+            conn.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_dont_fragment(_conn: &UdpSocket, on: bool) -> io::Result<()> {
+    if !on {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DF not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::test_client;
+
+    #[test]
+    fn padded_bind_req_size_test() {
+        let client = test_client();
+        for size in [MIN_PROBE_SIZE, 1400, 1472, MAX_PROBE_SIZE] {
+            let pkt = client.new_padded_bind_req(size).unwrap();
+            assert_eq!(pkt.bytes().len(), size);
+        }
+        assert!(client.new_padded_bind_req(20).is_err());
+    }
+
+    #[test]
+    fn probe_ceiling_test() {
+        let max = probe_ceiling(&"127.0.0.1".parse().unwrap(), false);
+        assert!((MIN_PROBE_SIZE..=MAX_PROBE_SIZE).contains(&max));
+        assert_eq!(max % 4, 0);
+        // No interface has this address, Ethernet is assumed.
+        assert_eq!(
+            probe_ceiling(&"192.0.2.1".parse().unwrap(), false),
+            ETHERNET_MTU - 28
+        );
+        assert_eq!(
+            probe_ceiling(&"2001:db8::1".parse().unwrap(), true),
+            ETHERNET_MTU - 48
+        );
+    }
+
+    #[test]
+    fn probe_path_mtu_test() {
+        // Reads datagrams into a 1500 byte buffer, larger probes are
+        // truncated and go unanswered.
+        let server = crate::Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve());
+
+        let mut client = test_client();
+        let policy = client.retransmit_policy;
+        let conn = client.conn.clone();
+        let start = std::time::Instant::now();
+        let size = client.probe_path_mtu(&conn, server_addr).unwrap();
+        assert!((1500 - PROBE_GRANULARITY..=1500).contains(&size));
+        assert!(start.elapsed() < Duration::from_secs(15));
+        assert_eq!(client.retransmit_policy, policy);
+        assert_eq!(client.recv_buffer_size, size.max(DEFAULT_RECV_BUFFER_SIZE));
+    }
+}
@@ -1,99 +1,454 @@
 use std::io;
-use std::net::UdpSocket;
+use std::net::SocketAddr;
 
+use crate::secret::check_message_integrity;
+use crate::transport::{is_icmp_error, send_to};
+use crate::utils::to_hex;
 use crate::Attribute;
 use crate::Host;
 use crate::Packet;
-use crate::TYPE_BINDING_REQUEST;
+use crate::RttEstimate;
+use crate::Transport;
+use crate::{
+    ATTRIBUTE_CHANGE_REQUEST, ATTRIBUTE_FINGERPRINT, ATTRIBUTE_MESSAGE_INTEGRITY,
+    ATTRIBUTE_PADDING, ATTRIBUTE_RESPONSE_ADDRESS, ATTRIBUTE_TRANSACTION_TRANSMIT_COUNTER,
+    ATTRIBUTE_USERNAME, TYPE_BINDING_REQUEST,
+};
 use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
 
 use super::Client;
 use super::Response;
 
-const NUM_RETRANSMIT: usize = 9;
-const DEFAULT_TIMEOUT: u64 = 100;
-const MAX_TIMEOUT: u64 = 1600;
-const MAX_PACKET_SIZE: usize = 1024;
+// Large enough for a full Ethernet MTU worth of response.
+pub const DEFAULT_RECV_BUFFER_SIZE: usize = 1500;
 
 impl Client {
     pub fn send_bind_req(
         &self,
-        conn: &UdpSocket,
+        conn: &dyn Transport,
         addr: std::net::SocketAddr,
         change_ip: bool,
         change_port: bool,
     ) -> Result<Response, String> {
+        self.send_bind_req_with_attributes(conn, addr, change_ip, change_port, Vec::new())
+    }
+
+    // Same as send_bind_req, with extra attributes (e.g. PADDING) placed
+    // before the FINGERPRINT.
+    pub fn send_bind_req_with_attributes(
+        &self,
+        conn: &dyn Transport,
+        addr: std::net::SocketAddr,
+        change_ip: bool,
+        change_port: bool,
+        extra: Vec<Attribute>,
+    ) -> Result<Response, String> {
+        let pkt = self.new_bind_req(change_ip, change_port, extra);
+        self.send(pkt, conn, addr).map_err(|e| e.to_string())
+    }
+
+    pub fn new_bind_req(
+        &self,
+        change_ip: bool,
+        change_port: bool,
+        extra: Vec<Attribute>,
+    ) -> Packet {
+        if self.classic {
+            let mut pkt = new_classic_bind_req(change_ip, change_port, extra);
+            self.sign(&mut pkt);
+            return pkt;
+        }
+
         let mut pkt = Packet::new();
         pkt.types = TYPE_BINDING_REQUEST;
-        let mut attribute = Attribute::new_software_attribute(&self.software_name);
-        pkt.add_attribute(attribute.clone());
+        // An empty name leaves SOFTWARE out.
+        if !self.software_name.is_empty() {
+            pkt.add_attribute(Attribute::new_software_attribute(&self.software_name));
+        }
         if change_ip || change_port {
-            attribute = Attribute::new_change_req_attribute(change_ip, change_port);
-            pkt.add_attribute(attribute.clone());
+            pkt.add_attribute(Attribute::new_change_req_attribute(change_ip, change_port));
+        }
+        for a in extra {
+            pkt.add_attribute(a);
+        }
+        if self.ecn.is_some() {
+            pkt.add_attribute(Attribute::new_ecn_check_attribute(None));
+        }
+        self.sign(&mut pkt);
+
+        if self.fingerprint {
+            pkt.length += 8;
+            let attribute = Attribute::new_fingerprint_attribute(&pkt);
+            pkt.length -= 8;
+            pkt.add_attribute(attribute);
+        }
+        pkt
+    }
+
+    // USERNAME and MESSAGE-INTEGRITY from the shared secret, if any.
+    fn sign(&self, pkt: &mut Packet) {
+        if let Some(credentials) = &self.credentials {
+            pkt.add_attribute(Attribute::new_username_attribute(&credentials.username));
+            pkt.add_message_integrity(credentials.password.as_bytes());
         }
+    }
 
-        pkt.length += 8;
-        attribute = Attribute::new_fingerprint_attribute(&pkt);
-        pkt.length -= 8;
-        pkt.add_attribute(attribute.clone());
+    // `pkt` with TRANSACTION_TRANSMIT_COUNTER set to `count`, signed and
+    // fingerprinted again.
+    fn transmission(&self, pkt: &Packet, count: u8) -> Packet {
+        let mut out = Packet {
+            types: pkt.types,
+            length: 0,
+            trans_id: pkt.trans_id,
+            attributes: Vec::new(),
+        };
+        let mut fingerprint = false;
+        for a in pkt.attributes.iter() {
+            match a.s_type {
+                ATTRIBUTE_FINGERPRINT => fingerprint = true,
+                ATTRIBUTE_USERNAME | ATTRIBUTE_MESSAGE_INTEGRITY => {}
+                ATTRIBUTE_TRANSACTION_TRANSMIT_COUNTER => {}
+                _ => out.add_attribute(a.clone()),
+            }
+        }
+        out.add_attribute(Attribute::new_transmit_counter_attribute(count, 0));
+        self.sign(&mut out);
+        if fingerprint {
+            out.length += 8;
+            let attribute = Attribute::new_fingerprint_attribute(&out);
+            out.length -= 8;
+            out.add_attribute(attribute);
+        }
+        out
+    }
 
-        match self.send(pkt, conn, addr) {
-            Ok(res) => return Ok(res),
-            Err(e) => return Err(e.to_string()),
+    // Binding request asking the server to send the response to
+    // `response_addr` (RFC 3489 RESPONSE-ADDRESS). The response carries
+    // REFLECTED-FROM; send() only sees it if `response_addr` is this socket.
+    pub fn new_bind_req_with_response_addr(
+        &self,
+        change_ip: bool,
+        change_port: bool,
+        response_addr: &SocketAddr,
+    ) -> Packet {
+        let extra = vec![Attribute::new_addr_attribute(
+            ATTRIBUTE_RESPONSE_ADDRESS,
+            response_addr,
+        )];
+        self.new_bind_req(change_ip, change_port, extra)
+    }
+
+    pub(crate) fn send(
+        &self,
+        pkt: Packet,
+        conn: &dyn Transport,
+        addr: std::net::SocketAddr,
+    ) -> Result<Response, io::Error> {
+        let ecn = match self.ecn {
+            Some(ecn) => ecn,
+            None => return self.exchange(pkt, conn, addr),
         };
+        // Only this transaction is marked, not whatever the socket sends next.
+        // Other senders on `conn` meanwhile are marked too: a socket shared
+        // with other threads or a TransactionManager must not be used with
+        // `ecn` set, Client::check_ecn uses a socket of its own.
+        let previous = conn.ecn()?;
+        conn.set_ecn(ecn)?;
+        let result = self.exchange(pkt, conn, addr);
+        if let Err(e) = conn.set_ecn(previous) {
+            warn!(error = %e, "can't restore the ECN field of the socket");
+        }
+        let mut resp = result?;
+        resp.ecn_sent = Some(ecn);
+        Ok(resp)
     }
 
-    fn send(
+    fn exchange(
         &self,
         pkt: Packet,
-        conn: &UdpSocket,
+        conn: &dyn Transport,
         addr: std::net::SocketAddr,
     ) -> Result<Response, io::Error> {
-        let mut timeout = DEFAULT_TIMEOUT;
+        let policy = self.retransmit_policy;
+        let rto = policy.rto(self.rtt_estimate(&addr).as_ref());
+        let total_deadline = policy.deadline(Instant::now());
 
-        let mut packet_bytes = vec![0u8; MAX_PACKET_SIZE];
+        let local_addr = conn.local_addr()?;
+        let mut request = pkt.bytes();
+        let mut packet_bytes = vec![0u8; self.recv_buffer_size];
+        let mut sent_at = Vec::with_capacity(policy.rc as usize);
 
-        for _ in 0..NUM_RETRANSMIT {
-            let length = conn.send_to(&pkt.bytes(), addr.clone())?;
+        for attempt in 0..policy.rc {
+            // RFC 7982: every transmission carries its own number, so a
+            // response tells which one it answers.
+            if self.transmit_counter {
+                request = self
+                    .transmission(&pkt, (attempt + 1).min(255) as u8)
+                    .bytes();
+            }
+            sent_at.push(Instant::now());
+            let length = send_to(conn, &request, addr)?;
+            trace!(
+                server = %addr,
+                trans_id = %to_hex(&pkt.trans_id),
+                attempt,
+                length,
+                "request sent"
+            );
 
-            if length != pkt.bytes().len() {
-                return Err(io::Error::new(io::ErrorKind::Other, "Asymmetric length"));
+            if length != request.len() {
+                return Err(io::Error::other("Asymmetric length"));
             }
 
-            let deadline = Instant::now() + Duration::from_millis(timeout as u64);
-            conn.set_read_timeout(Some(deadline.duration_since(Instant::now())))?;
+            let deadline =
+                policy.timeout_at(attempt, rto, sent_at[attempt as usize], total_deadline);
 
-            if timeout < MAX_TIMEOUT {
-                timeout *= 2
-            }
             loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                conn.set_read_timeout(Some(deadline - now))?;
+
                 let (lengths, raddr) = match conn.recv_from(&mut packet_bytes) {
                     Ok(v) => v,
-                    Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
-                            continue; // 非阻塞模式下，如果没有数据，继续循环
-                        }
-                        break;
+                    // 超时，重传
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        break
                     }
+                    // An ICMP error doesn't mean no response will come, nor
+                    // that the socket has no other peer: keep to the RTO
+                    // schedule.
+                    Err(e) if is_icmp_error(&e) => {
+                        debug!(server = %addr, error = %e, "ignoring ICMP error");
+                        continue;
+                    }
+                    Err(e) => return Err(e),
                 };
-                let p = Packet::new_packet_form_bytes(packet_bytes[..lengths].to_vec());
-
-                let p_pkt: Packet = match p {
+                let p_pkt = match Packet::new_packet_form_bytes(packet_bytes[..lengths].to_vec()) {
                     Ok(v) => v,
-                    Err(e) => {
-                        return Err(io::Error::new(io::ErrorKind::Other, e));
-                    }
+                    Err(_) => continue,
                 };
 
                 if pkt.trans_id != p_pkt.trans_id {
+                    trace!(from = %raddr, "ignoring response to another transaction");
                     continue;
                 }
-                let mut resp = Response::new(p_pkt, &raddr);
-                resp.server_addr = Some(Host::new(&raddr.ip().to_string())?);
+                // RFC 3489 section 9.3: with a shared secret, a response
+                // without a valid MESSAGE-INTEGRITY is discarded as if it
+                // was never received.
+                if let Some(credentials) = &self.credentials {
+                    if let Err(e) = check_message_integrity(
+                        &packet_bytes[..lengths],
+                        credentials.password.as_bytes(),
+                    ) {
+                        debug!(from = %raddr, error = %e, "ignoring unauthenticated response");
+                        continue;
+                    }
+                }
+
+                // Karn's algorithm: without the echoed counter a response to
+                // a retransmitted request can't tell which transmission it
+                // answers.
+                let answered = match p_pkt.get_transmit_counter() {
+                    Some((req, _)) if self.transmit_counter && req >= 1 => {
+                        sent_at.get(req as usize - 1).copied()
+                    }
+                    _ if attempt == 0 => Some(sent_at[0]),
+                    _ => None,
+                };
+                let rtt = answered.map(|t| t.elapsed());
+                debug!(
+                    from = %raddr,
+                    trans_id = %to_hex(&pkt.trans_id),
+                    ?rtt,
+                    retransmissions = attempt,
+                    "response received"
+                );
+                if let Some(rtt) = rtt {
+                    self.update_rtt(addr, rtt);
+                }
+                if is_plain(&pkt) {
+                    self.record_success(addr, rtt);
+                }
+                let mut resp = Response::new(p_pkt, &local_addr);
+                resp.server_addr = Some(Host::from(raddr));
+                resp.rtt = rtt;
+                resp.retransmissions = attempt;
                 return Ok(resp);
             }
+
+            if !policy.may_retransmit(attempt, Instant::now(), total_deadline) {
+                break;
+            }
+        }
+
+        debug!(server = %addr, trans_id = %to_hex(&pkt.trans_id), "request timed out");
+        if is_plain(&pkt) {
+            self.record_timeout(addr);
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "Request timed out"))
+    }
+
+    pub fn rtt_estimate(&self, addr: &SocketAddr) -> Option<RttEstimate> {
+        self.rtt_estimates.lock().ok()?.get(addr).copied()
+    }
+
+    fn update_rtt(&self, addr: SocketAddr, sample: Duration) {
+        if let Ok(mut estimates) = self.rtt_estimates.lock() {
+            estimates.entry(addr).or_default().update(sample);
         }
+    }
+}
+
+// Whether the server is expected to answer the request at its own address:
+// CHANGE-REQUEST, RESPONSE-ADDRESS and PADDING requests go unanswered for
+// reasons that say nothing about the server's health.
+fn is_plain(pkt: &Packet) -> bool {
+    !pkt.attributes.iter().any(|a| {
+        matches!(
+            a.s_type,
+            ATTRIBUTE_CHANGE_REQUEST | ATTRIBUTE_RESPONSE_ADDRESS | ATTRIBUTE_PADDING
+        )
+    })
+}
+
+// RFC 3489 servers know neither SOFTWARE nor FINGERPRINT.
+fn new_classic_bind_req(change_ip: bool, change_port: bool, extra: Vec<Attribute>) -> Packet {
+    let mut pkt = Packet::new_classic();
+    pkt.types = TYPE_BINDING_REQUEST;
+    if change_ip || change_port {
+        pkt.add_attribute(Attribute::new_change_req_attribute(change_ip, change_port));
+    }
+    for a in extra {
+        pkt.add_attribute(a);
+    }
+    pkt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{test_client, test_client_builder};
+    use crate::{Credentials, Server};
+    use std::net::UdpSocket;
+    use std::thread;
+
+    #[test]
+    fn send_total_timeout_test() {
+        let mut client = test_client_builder(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        client.retransmit_policy.initial_rto = Duration::from_millis(10);
+        // Nobody answers on this socket.
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let start = Instant::now();
+        let err = client
+            .send_bind_req(&conn, silent.local_addr().unwrap(), false, false)
+            .unwrap_err();
+        assert_eq!(err, "Request timed out");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    // The first transmission hits a closed port, the server is up by the
+    // retransmission. Connected sockets get the ICMP error on Linux.
+    #[test]
+    fn port_unreachable_test() {
+        let mut client = test_client();
+        client.retransmit_policy.initial_rto = Duration::from_millis(200);
+        let server_addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        conn.connect(server_addr).unwrap();
+
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let server = Server::bind(&server_addr.to_string(), "stun".to_string()).unwrap();
+            server.serve_one().unwrap();
+        });
+        let resp = client
+            .send_bind_req(&conn, server_addr, false, false)
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(resp.retransmissions, 1);
+    }
+
+    #[test]
+    fn unsigned_response_test() {
+        // Answers without MESSAGE-INTEGRITY, it has no credentials.
+        let mut server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        server.classic = true;
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve_one().unwrap());
+
+        let mut client = test_client_builder(Duration::from_millis(200))
+            .classic(true)
+            .credentials(Credentials::new("user", "password"))
+            .build()
+            .unwrap();
+        client.retransmit_policy.rc = 1;
+        let err = client
+            .send_bind_req(&client.conn, server_addr, false, false)
+            .unwrap_err();
+        assert_eq!(err, "Request timed out");
+    }
+
+    #[test]
+    fn signed_response_test() {
+        let credentials = Credentials::new("user", "password");
+        let mut server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        server.classic = true;
+        server.credentials = Some(credentials.clone());
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve_one().unwrap());
+
+        let client = test_client_builder(Duration::from_secs(2))
+            .classic(true)
+            .credentials(credentials)
+            .build()
+            .unwrap();
+        let resp = client
+            .send_bind_req(&client.conn, server_addr, false, false)
+            .unwrap();
+        assert_eq!(
+            resp.mapped_addr.unwrap().string(),
+            client.conn.local_addr().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn transmit_counter_test() {
+        let server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        // Loses the first transmission.
+        thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            server.conn.recv_from(&mut buf).unwrap();
+            let (n, from) = server.conn.recv_from(&mut buf).unwrap();
+            let (resp, dest) = server.response(&buf[..n], from).unwrap();
+            server.conn.send_to(&resp.bytes(), dest).unwrap();
+        });
 
-        return Err(io::Error::new(io::ErrorKind::Other, "fail"));
+        let mut client = test_client_builder(Duration::from_secs(2))
+            .transmit_counter(true)
+            .build()
+            .unwrap();
+        client.retransmit_policy.initial_rto = Duration::from_millis(20);
+        let resp = client
+            .send_bind_req(&client.conn, server_addr, false, false)
+            .unwrap();
+        assert_eq!(resp.transmit_counter, Some((2, 1)));
+        assert_eq!(resp.retransmissions, 1);
+        // The counter tells which transmission was answered.
+        assert!(resp.rtt.unwrap() < Duration::from_millis(20));
+        assert!(client.rtt_estimate(&server_addr).is_some());
     }
 }
@@ -1,23 +1,99 @@
 use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-use crate::utils::align;
 use crate::{
-    attribute, host, Host, ATTRIBUTE_CHANGED_ADDRESS, ATTRIBUTE_MAPPED_ADDRESS,
-    ATTRIBUTE_OTHER_ADDRESS, ATTRIBUTE_XOR_MAPPED_ADDRESS, ATTRIBUTE_XOR_MAPPED_ADDRESS_EXP,
-    MAGIC_COOKIE,
+    Host, ATTRIBUTE_CHANGED_ADDRESS, ATTRIBUTE_FINGERPRINT, ATTRIBUTE_MAPPED_ADDRESS,
+    ATTRIBUTE_OTHER_ADDRESS, ATTRIBUTE_REFLECTED_FROM, ATTRIBUTE_RESPONSE_ADDRESS,
+    ATTRIBUTE_SOURCE_ADDRESS, ATTRIBUTE_TRANSACTION_TRANSMIT_COUNTER, ATTRIBUTE_XOR_MAPPED_ADDRESS,
+    ATTRIBUTE_XOR_MAPPED_ADDRESS_EXP, FINGERPRINT, MAGIC_COOKIE,
 };
 
 use super::utils;
 use super::Attribute;
 use rand::thread_rng;
 use rand::Rng;
-use std::f32::consts::E;
-use std::fmt::Binary;
-use std::io::{self, Read, Write};
-use std::task::ready;
-use std::thread::panicking;
-use std::vec;
+use std::{error, fmt};
 
+const HEADER_SIZE: usize = 20;
+
+// How much Packet::decode tolerates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeMode {
+    // Everything RFC 5389 section 6 requires: magic cookie, length a
+    // multiple of 4 matching the datagram, padded attributes and a correct
+    // FINGERPRINT in last position.
+    Strict,
+    // Accepts RFC 3489 messages without cookie, trailing bytes after the
+    // message and a missing padding at the end.
+    Lenient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    RFC3489,
+    RFC5389,
+}
+
+// Why a message was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    TooShort(usize),
+    TooLong(usize),
+    LeadingBits(u16), // the two most significant bits of the type are not 0
+    NoMagicCookie(u32),
+    UnalignedLength(u16),
+    LengthMismatch { header: u16, actual: usize },
+    TruncatedAttribute { offset: usize }, // offset from the end of the header
+    FingerprintMismatch,
+    FingerprintNotLast,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::TooShort(n) => write!(f, "Received data length too short: {} bytes", n),
+            PacketError::TooLong(n) => write!(f, "Received data length too long: {} bytes", n),
+            PacketError::LeadingBits(t) => {
+                write!(f, "Not a STUN message: message type {:#06x}", t)
+            }
+            PacketError::NoMagicCookie(c) => write!(f, "Missing magic cookie: {:#010x}", c),
+            PacketError::UnalignedLength(n) => {
+                write!(f, "Message length {} is not a multiple of 4", n)
+            }
+            PacketError::LengthMismatch { header, actual } => write!(
+                f,
+                "Message length {} does not match the {} bytes received",
+                header, actual
+            ),
+            PacketError::TruncatedAttribute { offset } => {
+                write!(f, "Attribute at offset {} is truncated", offset)
+            }
+            PacketError::FingerprintMismatch => write!(f, "FINGERPRINT does not match"),
+            PacketError::FingerprintNotLast => write!(f, "FINGERPRINT is not the last attribute"),
+        }
+    }
+}
+
+impl error::Error for PacketError {}
+
+fn align_len(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+// `offset` is where the FINGERPRINT attribute starts in `buf`.
+fn check_fingerprint(buf: &[u8], offset: usize, value: &[u8]) -> Result<(), PacketError> {
+    if value.len() != 4 {
+        return Err(PacketError::FingerprintMismatch);
+    }
+    let crc = crc32fast::hash(&buf[..offset]) ^ FINGERPRINT;
+    if BigEndian::read_u32(value) != crc {
+        return Err(PacketError::FingerprintMismatch);
+    }
+    Ok(())
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Packet {
     pub types: u16,
@@ -26,6 +102,12 @@ pub struct Packet {
     pub attributes: Vec<Attribute>,
 }
 
+impl Default for Packet {
+    fn default() -> Self {
+        Packet::new()
+    }
+}
+
 impl Packet {
     pub fn new() -> Packet {
         let mut trans_id = [0u8; 16];
@@ -40,92 +122,165 @@ impl Packet {
             attributes: Vec::new(),
         }
     }
+    // RFC 3489 message: the transaction id is 16 random bytes, without the
+    // magic cookie.
+    pub fn new_classic() -> Packet {
+        let mut trans_id = [0u8; 16];
+        let mut rng = thread_rng();
+        loop {
+            rng.fill(&mut trans_id[..]);
+            if BigEndian::read_u32(&trans_id[..4]) != MAGIC_COOKIE {
+                break;
+            }
+        }
+
+        Packet {
+            types: 0,
+            length: 0,
+            trans_id,
+            attributes: Vec::new(),
+        }
+    }
+
     pub fn new_packet_form_bytes(packet_bytes: Vec<u8>) -> Result<Packet, String> {
-        if packet_bytes.len() < 20 {
-            return Err("Received data length too short".to_string());
-        } else if (packet_bytes.len() - 20) > u16::MAX as usize {
-            return Err("Received data length too long".to_string());
+        Packet::decode(&packet_bytes, DecodeMode::Lenient).map_err(|e| e.to_string())
+    }
+
+    // Validates the message against RFC 5389 section 6 while decoding it.
+    // See DecodeMode for what each mode accepts.
+    pub fn decode(buf: &[u8], mode: DecodeMode) -> Result<Packet, PacketError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(PacketError::TooShort(buf.len()));
+        } else if buf.len() - HEADER_SIZE > u16::MAX as usize {
+            return Err(PacketError::TooLong(buf.len()));
         }
 
-        let types = BigEndian::read_u16(&packet_bytes[..2]);
-        let length = BigEndian::read_u16(&packet_bytes[2..4]);
-        let trans_id = packet_bytes[4..20].to_vec();
+        let types = BigEndian::read_u16(&buf[..2]);
+        if types & 0xc000 != 0 {
+            return Err(PacketError::LeadingBits(types));
+        }
+        let length = BigEndian::read_u16(&buf[2..4]);
+        let cookie = BigEndian::read_u32(&buf[4..8]);
+        let strict = mode == DecodeMode::Strict;
+        if strict && cookie != MAGIC_COOKIE {
+            return Err(PacketError::NoMagicCookie(cookie));
+        }
+        if strict && length % 4 != 0 {
+            return Err(PacketError::UnalignedLength(length));
+        }
+        let available = buf.len() - HEADER_SIZE;
+        if length as usize > available || (strict && (length as usize) < available) {
+            return Err(PacketError::LengthMismatch {
+                header: length,
+                actual: available,
+            });
+        }
+
+        let body = &buf[HEADER_SIZE..HEADER_SIZE + length as usize];
         let mut attributes: Vec<Attribute> = Vec::with_capacity(10);
-        let packet_bytes = packet_bytes[20..].to_vec();
-
-        let mut i = 4;
-        while i < packet_bytes.len() {
-            let p_types = BigEndian::read_u16(&packet_bytes[i..i + 2]);
-            let p_length = BigEndian::read_u16(&packet_bytes[i + 2..i + 4]);
-            let end = i as u16 + 4 + p_length;
-            if end < i as u16 + 4 || end > packet_bytes.len() as u16 {
-                return Err("Received data format mismatch".to_string());
+        let mut offset = 0;
+        while offset < body.len() {
+            if body.len() - offset < 4 {
+                return Err(PacketError::TruncatedAttribute { offset });
+            }
+            let a_type = BigEndian::read_u16(&body[offset..offset + 2]);
+            let a_length = BigEndian::read_u16(&body[offset + 2..offset + 4]) as usize;
+            let start = offset + 4;
+            // The padding of the last attribute may be missing in lenient mode.
+            let end = if strict {
+                align_len(a_length)
+            } else {
+                a_length
+            };
+            if body.len() - start < end {
+                return Err(PacketError::TruncatedAttribute { offset });
             }
-            let value = packet_bytes[i + 4..end as usize].to_vec();
-            // let attribute = Attribute::new(p_types,&value);
-            attributes.push(Attribute::new(p_types, &value));
-            i = (utils::align(p_length) + 4) as usize;
+            if strict && a_type == ATTRIBUTE_FINGERPRINT {
+                check_fingerprint(buf, HEADER_SIZE + offset, &body[start..start + a_length])?;
+                if start + align_len(a_length) != body.len() {
+                    return Err(PacketError::FingerprintNotLast);
+                }
+            }
+            attributes.push(Attribute::new(a_type, &body[start..start + a_length]));
+            offset = start + align_len(a_length);
         }
 
+        // Attribute::new pads every value, so the length is that of the
+        // padded attributes, which can exceed the header's in lenient mode.
+        let length =
+            u16::try_from(offset).map_err(|_| PacketError::TooLong(HEADER_SIZE + offset))?;
         Ok(Packet {
             types,
             length,
-            trans_id: utils::convert_vec_to_u8_array(&trans_id),
+            trans_id: buf[4..HEADER_SIZE].try_into().unwrap(),
             attributes,
         })
     }
 
+    // RFC 3489 has no magic cookie, the first 4 bytes of the transaction id
+    // are random.
+    pub fn version(&self) -> Version {
+        if BigEndian::read_u32(&self.trans_id[..4]) == MAGIC_COOKIE {
+            Version::RFC5389
+        } else {
+            Version::RFC3489
+        }
+    }
+
     pub fn add_attribute(&mut self, a: Attribute) {
         self.length += utils::align(a.length) + 4;
         self.attributes.push(a);
     }
 
     pub fn bytes(&self) -> Vec<u8> {
-        let mut packet_bytes = vec![0u8; 4];
-        BigEndian::write_u16(&mut packet_bytes[..2], self.types);
-        BigEndian::write_u16(&mut packet_bytes[2..4], self.length);
-        packet_bytes.extend(self.trans_id.clone());
-
-        for a in self.attributes.clone() {
-            let mut buf = vec![0u8; 2];
-            BigEndian::write_u16(&mut buf, a.s_type);
-            packet_bytes.extend(buf.clone());
-            BigEndian::write_u16(&mut buf, a.length);
-            packet_bytes.extend(buf.clone());
-            packet_bytes.extend(a.value);
-        }
-
+        let mut packet_bytes = vec![0u8; self.encoded_len()];
+        // The buffer is exactly encoded_len() bytes, this can't fail.
+        let _ = self.encode_into(&mut packet_bytes);
         packet_bytes
     }
 
     pub fn get_raw_addr(&self, attribute: u16) -> Option<Host> {
-        for mut a in self.attributes.clone() {
-            if a.s_type == attribute {
-                return Some(a.raw_addr());
-            }
-        }
-        None
+        self.attributes
+            .iter()
+            .find(|a| a.s_type == attribute)
+            .and_then(|a| a.raw_addr().ok())
     }
 
-    //  pub fn get_xor_Mapped_addr(&self) -> Option<Host> {
-    //       self.get_Xor_addr(ATTRIBUTE_XOR_MAPPED_ADDRESS)
-    //  }
-
     pub fn get_xor_addr(&self, attribute: u16) -> Option<Host> {
-        for a in self.attributes.clone() {
-            if a.s_type == attribute {
-                return Some(a.get_xor_addr(self.trans_id.to_vec()));
-            }
-        }
-        None
+        self.attributes
+            .iter()
+            .find(|a| a.s_type == attribute)
+            .and_then(|a| a.get_xor_addr(self.trans_id.to_vec()).ok())
     }
 
+    // XOR-MAPPED-ADDRESS, or its pre-RFC 5389 code point.
     pub fn get_xor_mapped_addr(&self) -> Option<Host> {
-        let mut addr = self.get_xor_addr(ATTRIBUTE_MAPPED_ADDRESS);
-        if let Some(_) = addr {
-            addr = self.get_xor_addr(ATTRIBUTE_XOR_MAPPED_ADDRESS_EXP);
-        }
-        addr
+        self.get_xor_addr(ATTRIBUTE_XOR_MAPPED_ADDRESS)
+            .or_else(|| self.get_xor_addr(ATTRIBUTE_XOR_MAPPED_ADDRESS_EXP))
+    }
+
+    pub fn get_mapped_addr(&self) -> Option<Host> {
+        self.get_raw_addr(ATTRIBUTE_MAPPED_ADDRESS)
+    }
+
+    pub fn get_response_addr(&self) -> Option<Host> {
+        self.get_raw_addr(ATTRIBUTE_RESPONSE_ADDRESS)
+    }
+
+    pub fn get_source_addr(&self) -> Option<Host> {
+        self.get_raw_addr(ATTRIBUTE_SOURCE_ADDRESS)
+    }
+
+    pub fn get_reflected_from(&self) -> Option<Host> {
+        self.get_raw_addr(ATTRIBUTE_REFLECTED_FROM)
+    }
+
+    // (Req, Resp) of TRANSACTION_TRANSMIT_COUNTER.
+    pub fn get_transmit_counter(&self) -> Option<(u8, u8)> {
+        self.attributes
+            .iter()
+            .find(|a| a.s_type == ATTRIBUTE_TRANSACTION_TRANSMIT_COUNTER && a.value.len() >= 4)
+            .map(|a| (a.value[2], a.value[3]))
     }
 
     pub fn get_change_addr(&self) -> Option<Host> {
@@ -136,3 +291,219 @@ impl Packet {
         self.get_raw_addr(ATTRIBUTE_OTHER_ADDRESS)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Ecn, ATTRIBUTE_ALTERNATE_SERVER, ATTRIBUTE_MAPPED_ADDRESS, ATTRIBUTE_OTHER_ADDRESS,
+        ATTRIBUTE_REFLECTED_FROM, ATTRIBUTE_RESPONSE_ADDRESS, ATTRIBUTE_SOURCE_ADDRESS,
+        ATTRIBUTE_XOR_MAPPED_ADDRESS, TYPE_BINDING_REQUEST,
+    };
+    use proptest::prelude::*;
+    use std::net::{Ipv6Addr, SocketAddr};
+
+    fn packet() -> Vec<u8> {
+        let mut pkt = Packet::new();
+        pkt.types = TYPE_BINDING_REQUEST;
+        pkt.add_attribute(Attribute::new_software_attribute("stun"));
+        pkt.add_attribute(Attribute::new_change_req_attribute(false, true));
+        pkt.length += 8;
+        let fingerprint = Attribute::new_fingerprint_attribute(&pkt);
+        pkt.length -= 8;
+        pkt.add_attribute(fingerprint);
+        pkt.bytes()
+    }
+
+    #[test]
+    fn decode_test() {
+        let bytes = packet();
+        let pkt = Packet::decode(&bytes, DecodeMode::Strict).unwrap();
+        assert_eq!(pkt.attributes.len(), 3);
+        assert_eq!(pkt.attributes[0].value, b"stun");
+        assert_eq!(pkt.version(), Version::RFC5389);
+        assert_eq!(pkt.bytes(), bytes);
+    }
+
+    #[test]
+    fn decode_strict_errors_test() {
+        let bytes = packet();
+        let strict = |b: &[u8]| Packet::decode(b, DecodeMode::Strict).unwrap_err();
+
+        assert_eq!(strict(&bytes[..19]), PacketError::TooShort(19));
+
+        let mut b = bytes.clone();
+        b[0] |= 0x80;
+        assert_eq!(strict(&b), PacketError::LeadingBits(0x8001));
+
+        let mut b = bytes.clone();
+        b[4] = 0;
+        assert!(matches!(strict(&b), PacketError::NoMagicCookie(_)));
+
+        let mut b = bytes.clone();
+        b.push(0);
+        assert!(matches!(strict(&b), PacketError::LengthMismatch { .. }));
+
+        let mut b = bytes.clone();
+        b[3] += 1;
+        assert_eq!(strict(&b), PacketError::UnalignedLength(b[3] as u16));
+
+        let mut b = bytes.clone();
+        let n = b.len();
+        b[n - 1] ^= 1;
+        assert_eq!(strict(&b), PacketError::FingerprintMismatch);
+
+        // An attribute claiming more bytes than the message holds.
+        let mut b = bytes.clone();
+        b[23] = 0xff;
+        assert_eq!(strict(&b), PacketError::TruncatedAttribute { offset: 0 });
+    }
+
+    #[test]
+    fn decode_lenient_test() {
+        let mut bytes = packet();
+        // RFC 3489: random bytes instead of the cookie.
+        bytes[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        bytes.extend_from_slice(&[0, 0]);
+        let pkt = Packet::decode(&bytes, DecodeMode::Lenient).unwrap();
+        assert_eq!(pkt.version(), Version::RFC3489);
+        assert_eq!(pkt.attributes.len(), 3);
+
+        // Missing padding after the last attribute.
+        let mut bytes = Packet::new().bytes();
+        bytes[3] = 7;
+        bytes.extend_from_slice(&[0x80, 0x22, 0, 3, b'a', b'b', b'c']);
+        let decoded = Packet::decode(&bytes, DecodeMode::Lenient).unwrap();
+        assert_eq!(decoded.attributes[0].value, b"abc\0");
+        assert_eq!(decoded.length, 8);
+        assert_eq!(
+            Packet::decode(&decoded.bytes(), DecodeMode::Strict),
+            Ok(decoded)
+        );
+        assert!(Packet::decode(&bytes, DecodeMode::Strict).is_err());
+    }
+
+    fn socket_addr() -> impl Strategy<Value = SocketAddr> {
+        prop_oneof![
+            any::<([u8; 4], u16)>().prop_map(SocketAddr::from),
+            any::<([u8; 16], u16)>().prop_map(|(ip, port)| (Ipv6Addr::from(ip), port).into()),
+        ]
+    }
+
+    // Every attribute this crate builds, plus unknown ones.
+    fn attribute(trans_id: [u8; 16]) -> impl Strategy<Value = Attribute> {
+        let addr_type = prop::sample::select(vec![
+            ATTRIBUTE_MAPPED_ADDRESS,
+            ATTRIBUTE_RESPONSE_ADDRESS,
+            ATTRIBUTE_SOURCE_ADDRESS,
+            ATTRIBUTE_CHANGED_ADDRESS,
+            ATTRIBUTE_REFLECTED_FROM,
+            ATTRIBUTE_OTHER_ADDRESS,
+            ATTRIBUTE_ALTERNATE_SERVER,
+        ]);
+        prop_oneof![
+            ".{0,32}".prop_map(|s| Attribute::new_software_attribute(&s)),
+            any::<(bool, bool)>()
+                .prop_map(|(ip, port)| Attribute::new_change_req_attribute(ip, port)),
+            (0usize..600).prop_map(Attribute::new_padding_attribute),
+            any::<u16>().prop_map(Attribute::new_response_port_attribute),
+            any::<(u8, u8)>()
+                .prop_map(|(req, resp)| Attribute::new_transmit_counter_attribute(req, resp)),
+            "[a-z0-9]{0,24}".prop_map(|s| Attribute::new_username_attribute(&s)),
+            prop::option::of(0u8..4)
+                .prop_map(|bits| Attribute::new_ecn_check_attribute(bits.map(Ecn::from_bits))),
+            (addr_type, socket_addr())
+                .prop_map(|(t, addr)| Attribute::new_addr_attribute(t, &addr)),
+            socket_addr().prop_map(move |addr| {
+                Attribute::new_xor_addr_attribute(ATTRIBUTE_XOR_MAPPED_ADDRESS, &addr, &trans_id)
+            }),
+            (any::<u16>(), prop::collection::vec(any::<u8>(), 0..64))
+                .prop_filter("FINGERPRINT must be last", |(t, _)| *t
+                    != ATTRIBUTE_FINGERPRINT)
+                .prop_map(|(t, value)| Attribute::new(t, &value)),
+        ]
+    }
+
+    fn stun_packet() -> impl Strategy<Value = Packet> {
+        any::<[u8; 12]>().prop_flat_map(|id| {
+            let mut pkt = Packet::new();
+            pkt.trans_id[4..].copy_from_slice(&id);
+            (
+                Just(pkt.clone()),
+                0u16..0x4000,
+                prop::collection::vec(attribute(pkt.trans_id), 0..8),
+                any::<(bool, bool)>(),
+            )
+                .prop_map(|(mut pkt, types, attributes, (integrity, fingerprint))| {
+                    pkt.types = types;
+                    for a in attributes {
+                        pkt.add_attribute(a);
+                    }
+                    if integrity {
+                        pkt.add_message_integrity(b"key");
+                    }
+                    if fingerprint {
+                        pkt.length += 8;
+                        let attribute = Attribute::new_fingerprint_attribute(&pkt);
+                        pkt.length -= 8;
+                        pkt.add_attribute(attribute);
+                    }
+                    pkt
+                })
+        })
+    }
+
+    proptest! {
+        // encode -> decode -> encode
+        #[test]
+        fn round_trip_test(pkt in stun_packet()) {
+            let bytes = pkt.bytes();
+            let decoded = Packet::decode(&bytes, DecodeMode::Strict).unwrap();
+            prop_assert_eq!(&decoded, &pkt);
+            prop_assert_eq!(decoded.bytes(), bytes);
+        }
+
+        #[test]
+        fn addr_round_trip_test(addr in socket_addr(), id in any::<[u8; 12]>()) {
+            let mut pkt = Packet::new();
+            pkt.trans_id[4..].copy_from_slice(&id);
+            pkt.add_attribute(Attribute::new_addr_attribute(ATTRIBUTE_MAPPED_ADDRESS, &addr));
+            pkt.add_attribute(Attribute::new_xor_addr_attribute(
+                ATTRIBUTE_XOR_MAPPED_ADDRESS,
+                &addr,
+                &pkt.trans_id,
+            ));
+            let decoded = Packet::decode(&pkt.bytes(), DecodeMode::Strict).unwrap();
+            let mapped = decoded.get_mapped_addr().unwrap().string();
+            let xor_mapped = decoded.get_xor_mapped_addr().unwrap().string();
+            prop_assert_eq!(mapped.parse::<SocketAddr>(), Ok(addr));
+            prop_assert_eq!(xor_mapped.parse::<SocketAddr>(), Ok(addr));
+        }
+
+        // Hostile input gives errors, not panics.
+        #[test]
+        fn decode_any_test(
+            header in any::<[u8; 20]>(),
+            body in prop::collection::vec(any::<u8>(), 0..128),
+        ) {
+            let mut bytes = header.to_vec();
+            bytes[0] &= 0x3f;
+            BigEndian::write_u16(&mut bytes[2..4], body.len() as u16);
+            bytes.extend_from_slice(&body);
+            for buf in [&bytes[..], &bytes[20..]] {
+                if let Ok(pkt) = Packet::decode(buf, DecodeMode::Lenient) {
+                    for a in pkt.attributes.iter() {
+                        let _ = a.raw_addr();
+                        let _ = a.get_xor_addr(pkt.trans_id.to_vec());
+                    }
+                    let _ = pkt.get_xor_mapped_addr();
+                    let _ = pkt.get_transmit_counter();
+                    let _ = pkt.get_ecn_check();
+                    let _ = crate::secret::error_code(&pkt);
+                    let _ = crate::secret::check_message_integrity(buf, b"key");
+                    prop_assert!(Packet::decode(&pkt.bytes(), DecodeMode::Lenient).is_ok());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "bytes")]
+use bytes::{BufMut, BytesMut};
+
+use crate::{Attribute, Packet, PacketError, ATTRIBUTE_FINGERPRINT, FINGERPRINT};
+
+// Borrowed, allocation-free views over an encoded STUN message, and an
+// encoder writing straight into a caller-provided buffer.
+
+const HEADER_SIZE: usize = 20;
+const ATTRIBUTE_HEADER_SIZE: usize = 4;
+
+// One attribute inside a PacketRef. `value` excludes the padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttributeRef<'a> {
+    pub s_type: u16,
+    pub value: &'a [u8],
+}
+
+impl<'a> AttributeRef<'a> {
+    pub fn to_attribute(&self) -> Attribute {
+        Attribute::new(self.s_type, self.value)
+    }
+}
+
+// A STUN message parsed in place. Attribute bounds are checked once by
+// `parse`, iterating afterwards can't fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketRef<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> PacketRef<'a> {
+    // Accepts what Packet::decode accepts in lenient mode. Trailing bytes
+    // after the length given in the header are ignored.
+    pub fn parse(buf: &'a [u8]) -> Result<PacketRef<'a>, String> {
+        if buf.len() < HEADER_SIZE {
+            return Err("Received data length too short".to_string());
+        } else if buf.len() - HEADER_SIZE > u16::MAX as usize {
+            return Err(PacketError::TooLong(buf.len()).to_string());
+        }
+        let types = BigEndian::read_u16(&buf[..2]);
+        if types & 0xc000 != 0 {
+            return Err(PacketError::LeadingBits(types).to_string());
+        }
+        let length = BigEndian::read_u16(&buf[2..4]) as usize;
+        if buf.len() - HEADER_SIZE < length {
+            return Err("Received data format mismatch".to_string());
+        }
+
+        let body = &buf[HEADER_SIZE..HEADER_SIZE + length];
+        let mut offset = 0;
+        while offset < body.len() {
+            if body.len() - offset < ATTRIBUTE_HEADER_SIZE {
+                return Err("Received data format mismatch".to_string());
+            }
+            let value_len = BigEndian::read_u16(&body[offset + 2..offset + 4]) as usize;
+            if body.len() - offset - ATTRIBUTE_HEADER_SIZE < value_len {
+                return Err("Received data format mismatch".to_string());
+            }
+            offset += ATTRIBUTE_HEADER_SIZE + align_len(value_len);
+        }
+        // An unpadded last attribute can't take the padded length past
+        // what the header can express.
+        if offset > u16::MAX as usize {
+            return Err(PacketError::TooLong(HEADER_SIZE + offset).to_string());
+        }
+
+        Ok(PacketRef {
+            buf: &buf[..HEADER_SIZE + length],
+        })
+    }
+
+    pub fn types(&self) -> u16 {
+        BigEndian::read_u16(&self.buf[..2])
+    }
+
+    pub fn length(&self) -> u16 {
+        BigEndian::read_u16(&self.buf[2..4])
+    }
+
+    // 4 bytes magic cookie + 12 bytes transaction id
+    pub fn trans_id(&self) -> &'a [u8; 16] {
+        self.buf[4..HEADER_SIZE].try_into().unwrap()
+    }
+
+    pub fn attributes(&self) -> AttributeIter<'a> {
+        AttributeIter {
+            body: &self.buf[HEADER_SIZE..],
+            offset: 0,
+        }
+    }
+
+    // First attribute of the given type.
+    pub fn attribute(&self, s_type: u16) -> Option<AttributeRef<'a>> {
+        self.attributes().find(|a| a.s_type == s_type)
+    }
+
+    // The message as received, up to the length in the header.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        Packet {
+            types: self.types(),
+            length: self.length(),
+            trans_id: *self.trans_id(),
+            attributes: self.attributes().map(|a| a.to_attribute()).collect(),
+        }
+    }
+}
+
+pub struct AttributeIter<'a> {
+    body: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for AttributeIter<'a> {
+    type Item = AttributeRef<'a>;
+
+    fn next(&mut self) -> Option<AttributeRef<'a>> {
+        let rest = self.body.get(self.offset..)?;
+        if rest.len() < ATTRIBUTE_HEADER_SIZE {
+            return None;
+        }
+        let s_type = BigEndian::read_u16(&rest[..2]);
+        let value_len = BigEndian::read_u16(&rest[2..4]) as usize;
+        let value = rest.get(ATTRIBUTE_HEADER_SIZE..ATTRIBUTE_HEADER_SIZE + value_len)?;
+        self.offset += ATTRIBUTE_HEADER_SIZE + align_len(value_len);
+        Some(AttributeRef { s_type, value })
+    }
+}
+
+fn align_len(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+// Builds a message directly in `buf`. The header length is kept up to date
+// after every attribute, so the buffer holds a valid message at all times.
+pub struct PacketWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> PacketWriter<'a> {
+    pub fn new(buf: &'a mut [u8], types: u16, trans_id: &[u8; 16]) -> Result<Self, String> {
+        if buf.len() < HEADER_SIZE {
+            return Err("Buffer too small".to_string());
+        }
+        BigEndian::write_u16(&mut buf[..2], types);
+        BigEndian::write_u16(&mut buf[2..4], 0);
+        buf[4..HEADER_SIZE].copy_from_slice(trans_id);
+        Ok(PacketWriter {
+            buf,
+            len: HEADER_SIZE,
+        })
+    }
+
+    pub fn add_attribute(&mut self, s_type: u16, value: &[u8]) -> Result<(), String> {
+        let padded = align_len(value.len());
+        let end = self.len + ATTRIBUTE_HEADER_SIZE + padded;
+        if value.len() > u16::MAX as usize || end - HEADER_SIZE > u16::MAX as usize {
+            return Err("Attribute too long".to_string());
+        }
+        if end > self.buf.len() {
+            return Err("Buffer too small".to_string());
+        }
+        let start = self.len;
+        BigEndian::write_u16(&mut self.buf[start..start + 2], s_type);
+        BigEndian::write_u16(&mut self.buf[start + 2..start + 4], value.len() as u16);
+        let value_start = start + ATTRIBUTE_HEADER_SIZE;
+        self.buf[value_start..value_start + value.len()].copy_from_slice(value);
+        self.buf[value_start + value.len()..end].fill(0);
+        self.len = end;
+        BigEndian::write_u16(&mut self.buf[2..4], (self.len - HEADER_SIZE) as u16);
+        Ok(())
+    }
+
+    // FINGERPRINT must be the last attribute.
+    pub fn add_fingerprint(&mut self) -> Result<(), String> {
+        if self.len + 8 > self.buf.len() {
+            return Err("Buffer too small".to_string());
+        }
+        // The CRC covers the header with the length already including the
+        // fingerprint attribute.
+        BigEndian::write_u16(&mut self.buf[2..4], (self.len + 8 - HEADER_SIZE) as u16);
+        let crc = crc32fast::hash(&self.buf[..self.len]) ^ FINGERPRINT;
+        let mut value = [0u8; 4];
+        BigEndian::write_u32(&mut value, crc);
+        self.add_attribute(ATTRIBUTE_FINGERPRINT, &value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == HEADER_SIZE
+    }
+
+    // The finished message.
+    pub fn finish(self) -> &'a [u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Packet {
+    // Size of `bytes()` without building it.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE
+            + self
+                .attributes
+                .iter()
+                .map(|a| ATTRIBUTE_HEADER_SIZE + a.value.len())
+                .sum::<usize>()
+    }
+
+    // Writes the same bytes as `bytes()` into `buf`, returns the length.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, String> {
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err("Buffer too small".to_string());
+        }
+        BigEndian::write_u16(&mut buf[..2], self.types);
+        BigEndian::write_u16(&mut buf[2..4], self.length);
+        buf[4..HEADER_SIZE].copy_from_slice(&self.trans_id);
+        let mut offset = HEADER_SIZE;
+        for a in self.attributes.iter() {
+            BigEndian::write_u16(&mut buf[offset..offset + 2], a.s_type);
+            BigEndian::write_u16(&mut buf[offset + 2..offset + 4], a.length);
+            offset += ATTRIBUTE_HEADER_SIZE;
+            buf[offset..offset + a.value.len()].copy_from_slice(&a.value);
+            offset += a.value.len();
+        }
+        Ok(len)
+    }
+
+    #[cfg(feature = "bytes")]
+    pub fn encode_into_bytes_mut(&self, buf: &mut BytesMut) {
+        buf.reserve(self.encoded_len());
+        buf.put_u16(self.types);
+        buf.put_u16(self.length);
+        buf.put_slice(&self.trans_id);
+        for a in self.attributes.iter() {
+            buf.put_u16(a.s_type);
+            buf.put_u16(a.length);
+            buf.put_slice(&a.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ATTRIBUTE_SOFTWARE, TYPE_BINDING_REQUEST};
+
+    fn packet() -> Packet {
+        let mut pkt = Packet::new();
+        pkt.types = TYPE_BINDING_REQUEST;
+        pkt.add_attribute(Attribute::new_software_attribute("stun"));
+        pkt.add_attribute(Attribute::new_change_req_attribute(true, false));
+        pkt.length += 8;
+        let fingerprint = Attribute::new_fingerprint_attribute(&pkt);
+        pkt.length -= 8;
+        pkt.add_attribute(fingerprint);
+        pkt
+    }
+
+    #[test]
+    fn encode_into_test() {
+        let pkt = packet();
+        let mut buf = [0u8; 128];
+        let n = pkt.encode_into(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &pkt.bytes()[..]);
+        assert!(pkt.encode_into(&mut buf[..n - 1]).is_err());
+    }
+
+    #[test]
+    fn packet_ref_test() {
+        let pkt = packet();
+        let bytes = pkt.bytes();
+        let view = PacketRef::parse(&bytes).unwrap();
+        assert_eq!(view.types(), TYPE_BINDING_REQUEST);
+        assert_eq!(view.trans_id(), &pkt.trans_id);
+        assert_eq!(view.attributes().count(), 3);
+        assert_eq!(view.attribute(ATTRIBUTE_SOFTWARE).unwrap().value, b"stun");
+        assert_eq!(view.to_packet(), pkt);
+
+        assert!(PacketRef::parse(&bytes[..19]).is_err());
+        assert!(PacketRef::parse(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn leading_bits_test() {
+        let mut bytes = packet().bytes();
+        bytes[0] |= 0xc0;
+        assert!(PacketRef::parse(&bytes).is_err());
+        assert!(Packet::decode(&bytes, crate::DecodeMode::Lenient).is_err());
+    }
+
+    #[test]
+    fn too_long_test() {
+        let mut bytes = packet().bytes();
+        bytes.resize(HEADER_SIZE + u16::MAX as usize + 1, 0);
+        assert!(PacketRef::parse(&bytes).is_err());
+        assert!(Packet::decode(&bytes, crate::DecodeMode::Lenient).is_err());
+
+        // Up to the limit, trailing bytes are ignored.
+        bytes.pop();
+        assert!(PacketRef::parse(&bytes).is_ok());
+        assert!(Packet::decode(&bytes, crate::DecodeMode::Lenient).is_ok());
+    }
+
+    #[test]
+    fn packet_writer_test() {
+        let pkt = packet();
+        let mut buf = [0u8; 128];
+        let mut writer = PacketWriter::new(&mut buf, TYPE_BINDING_REQUEST, &pkt.trans_id).unwrap();
+        writer.add_attribute(ATTRIBUTE_SOFTWARE, b"stun").unwrap();
+        writer
+            .add_attribute(crate::ATTRIBUTE_CHANGE_REQUEST, &[0, 0, 0, 4])
+            .unwrap();
+        writer.add_fingerprint().unwrap();
+        assert_eq!(writer.finish(), &pkt.bytes()[..]);
+
+        let mut small = [0u8; 24];
+        let mut writer =
+            PacketWriter::new(&mut small, TYPE_BINDING_REQUEST, &pkt.trans_id).unwrap();
+        assert!(writer.add_attribute(ATTRIBUTE_SOFTWARE, b"stun").is_err());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn encode_into_bytes_mut_test() {
+        let pkt = packet();
+        let mut buf = BytesMut::new();
+        pkt.encode_into_bytes_mut(&mut buf);
+        assert_eq!(&buf[..], &pkt.bytes()[..]);
+    }
+}
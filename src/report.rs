@@ -0,0 +1,148 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Host, Response, NAT};
+
+// One request of the discovery process and what came back.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestStep {
+    pub name: String, // "Test I", "Test II" or "Test III"
+    pub server: SocketAddr,
+    pub change_ip: bool,
+    pub change_port: bool,
+    pub response_from: Option<Host>,
+    pub mapped_addr: Option<Host>,
+    pub changed_addr: Option<Host>,
+    pub other_addr: Option<Host>,
+    pub rtt: Option<Duration>, // until the response, retransmissions included
+    pub error: Option<String>,
+}
+
+// Everything discovery did and concluded, for storing and diffing.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveryReport {
+    pub servers: Vec<SocketAddr>, // every server address contacted, in order
+    pub steps: Vec<TestStep>,
+    pub nat: NAT,
+    pub mapped_addr: Option<Host>,
+    pub error: Option<String>,
+}
+
+impl Default for DiscoveryReport {
+    fn default() -> Self {
+        DiscoveryReport::new()
+    }
+}
+
+impl DiscoveryReport {
+    pub fn new() -> DiscoveryReport {
+        DiscoveryReport {
+            servers: Vec::new(),
+            steps: Vec::new(),
+            nat: NAT::NATUnknown,
+            mapped_addr: None,
+            error: None,
+        }
+    }
+
+    // Runs one test and records it.
+    pub fn run<F>(
+        &mut self,
+        name: &str,
+        server: SocketAddr,
+        change_ip: bool,
+        change_port: bool,
+        test: F,
+    ) -> Result<Response, String>
+    where
+        F: FnOnce() -> Result<Response, String>,
+    {
+        if !self.servers.contains(&server) {
+            self.servers.push(server);
+        }
+        let start = Instant::now();
+        let result = test();
+        let rtt = start.elapsed();
+
+        let mut step = TestStep {
+            name: name.to_string(),
+            server,
+            change_ip,
+            change_port,
+            response_from: None,
+            mapped_addr: None,
+            changed_addr: None,
+            other_addr: None,
+            rtt: None,
+            error: None,
+        };
+        match &result {
+            Ok(resp) => {
+                step.response_from = resp.server_addr;
+                step.mapped_addr = resp.mapped_addr;
+                step.changed_addr = resp.changed_addr;
+                step.other_addr = resp.other_addr;
+                step.rtt = Some(rtt);
+            }
+            Err(e) => step.error = Some(e.clone()),
+        }
+        self.steps.push(step);
+        result
+    }
+
+    pub fn finish(&mut self, nat: NAT, host: Result<Host, String>) {
+        self.nat = nat;
+        match host {
+            Ok(h) => self.mapped_addr = Some(h),
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    // The verdict in the shape Client::discover returns it.
+    pub fn result(&self) -> Result<Host, String> {
+        match (&self.error, &self.mapped_addr) {
+            (Some(e), _) => Err(e.clone()),
+            (None, Some(h)) => Ok(*h),
+            (None, None) => Err("No mapped address".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> DiscoveryReport {
+        let server: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let mut report = DiscoveryReport::new();
+        let _ = report.run("Test I", server, false, false, || {
+            Err("NAT BLOCKED".to_string())
+        });
+        report.finish(NAT::NATBlocked, Err("NATBlocked".to_string()));
+        report
+    }
+
+    #[test]
+    fn report_test() {
+        let report = report();
+        assert_eq!(report.servers.len(), 1);
+        assert_eq!(report.steps[0].error.as_deref(), Some("NAT BLOCKED"));
+        assert_eq!(report.steps[0].rtt, None);
+        assert_eq!(report.nat, NAT::NATBlocked);
+        assert_eq!(report.result(), Err("NATBlocked".to_string()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn report_json_test() {
+        let report = report();
+        let json = serde_json::to_string(&report).unwrap();
+        let decoded: DiscoveryReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, report);
+    }
+}
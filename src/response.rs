@@ -1,58 +1,69 @@
 use crate::utils;
 use std::net::SocketAddr;
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 use super::Host;
 use super::Packet;
+use crate::ecn::Ecn;
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Response {
-    pub packet: Packet,             // 原始服务器数据包
-    pub server_addr: Option<Host>,  // 接收数据包的地址
-    pub changed_addr: Option<Host>, // 从数据包解析的地址
-    pub mapped_addr: Option<Host>,  // 从数据包解析的地址，客户端 NAT 的外部地址
-    pub other_addr: Option<Host>,   // 从数据包解析的地址，用于 RFC 5780 中替换 changedAddr
-    pub identical: bool,            // 如果 mappedAddr 在本地地址列表中
+    pub packet: Packet,                     // 原始服务器数据包
+    pub server_addr: Option<Host>,          // 接收数据包的地址
+    pub changed_addr: Option<Host>,         // 从数据包解析的地址
+    pub mapped_addr: Option<Host>,          // 从数据包解析的地址，客户端 NAT 的外部地址
+    pub other_addr: Option<Host>,           // 从数据包解析的地址，用于 RFC 5780 中替换 changedAddr
+    pub identical: bool,                    // 映射地址就是本地套接字地址，即不在 NAT 后
+    pub source_addr: Option<Host>,          // RFC 3489 SOURCE-ADDRESS，服务器发送响应的地址
+    pub reflected_from: Option<Host>,       // RFC 3489 REFLECTED-FROM，RESPONSE-ADDRESS 请求的来源
+    pub ecn_sent: Option<Ecn>,              // 请求的 ECN 标记
+    pub ecn_received: Option<Ecn>,          // 服务器在 ECN-CHECK 中报告收到的 ECN 标记
+    pub rtt: Option<Duration>,              // 被响应的那次发送的往返时间，无法确定时为 None
+    pub retransmissions: u32,               // 收到响应前的重传次数
+    pub transmit_counter: Option<(u8, u8)>, // 服务器回显的传输计数 (Req, Resp)，RFC 7982
 }
 
 impl Response {
-    pub fn new(packet: Packet, conn: &SocketAddr) -> Self {
+    // `local_addr` is the address of the socket the response arrived on.
+    pub fn new(packet: Packet, local_addr: &SocketAddr) -> Self {
         let mut resp = Response {
-            packet: packet,
+            packet,
             server_addr: None,
             changed_addr: None,
             mapped_addr: None,
             other_addr: None,
             identical: false,
+            source_addr: None,
+            reflected_from: None,
+            ecn_sent: None,
+            ecn_received: None,
+            rtt: None,
+            retransmissions: 0,
+            transmit_counter: None,
         };
 
+        // RFC 3489 servers only send MAPPED-ADDRESS.
         let mapped_addr = resp.packet.get_xor_mapped_addr();
         resp.mapped_addr = if let Some(mapped_addr) = mapped_addr {
             Some(mapped_addr)
         } else {
-            resp.packet.get_xor_mapped_addr()
+            resp.packet.get_mapped_addr()
         };
+        resp.source_addr = resp.packet.get_source_addr();
+        resp.reflected_from = resp.packet.get_reflected_from();
+        resp.ecn_received = resp.packet.get_ecn_check();
+        resp.transmit_counter = resp.packet.get_transmit_counter();
 
-        let local_addr_str = conn.ip().to_string();
-        if let Some(addr) = resp.mapped_addr.clone() {
-            let mapped_addr_str = addr.string();
-            resp.identical = utils::is_local_addrss(&local_addr_str, &mapped_addr_str)
-        }
-
-        if let Some(addr) = resp.packet.get_change_addr() {
-            let change_addr_host = Host::new(&addr.string());
-            match change_addr_host {
-                Ok(h) => resp.changed_addr = Some(h),
-                Err(_) => resp.changed_addr = None,
-            }
+        if let Some(mapped) = resp.mapped_addr {
+            resp.identical = utils::is_local_addr(local_addr, &mapped.addr);
         }
 
-        if let Some(addr) = resp.packet.get_other_addr() {
-            let addr_host = Host::new(&addr.string());
-            match addr_host {
-                Ok(h) => resp.other_addr = Some(h),
-                Err(_) => resp.other_addr = None,
-            }
-        }
+        resp.changed_addr = resp.packet.get_change_addr();
+        resp.other_addr = resp.packet.get_other_addr();
 
         resp
     }
@@ -0,0 +1,193 @@
+use std::time::{Duration, Instant};
+
+// Retransmission of requests over UDP (RFC 5389 section 7.2.1).
+//
+// A request is sent up to `rc` times. The wait after the first transmission
+// is the RTO, doubled after every retransmission (capped at `max_rto`); the
+// wait after the last one is `rm` times the RTO. `total_timeout`, if set,
+// bounds the whole transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetransmitPolicy {
+    pub initial_rto: Duration, // RTO used until an RTT estimate exists
+    pub rc: u32,               // Rc: total number of transmissions
+    pub rm: u32,               // Rm: last wait is rm * RTO
+    pub min_rto: Duration,     // lower bound for an RTO computed from RTT
+    pub max_rto: Duration,     // upper bound for the doubled RTO
+    pub total_timeout: Option<Duration>, // deadline for the whole transaction
+}
+
+impl Default for RetransmitPolicy {
+    // 100ms doubling up to 1600ms, 9 transmissions, ~9.5s in total.
+    fn default() -> Self {
+        RetransmitPolicy {
+            initial_rto: Duration::from_millis(100),
+            rc: 9,
+            rm: 16,
+            min_rto: Duration::from_millis(10),
+            max_rto: Duration::from_millis(1600),
+            total_timeout: Some(Duration::from_millis(9500)),
+        }
+    }
+}
+
+impl RetransmitPolicy {
+    // The values suggested by RFC 5389: RTO 500ms, Rc 7, Rm 16 (39.5s).
+    pub fn rfc5389() -> Self {
+        RetransmitPolicy {
+            initial_rto: Duration::from_millis(500),
+            rc: 7,
+            rm: 16,
+            min_rto: Duration::from_millis(10),
+            max_rto: Duration::from_secs(60),
+            total_timeout: Some(Duration::from_millis(39500)),
+        }
+    }
+
+    // RTO for a new transaction, from the RTT estimate when there is one.
+    pub fn rto(&self, estimate: Option<&RttEstimate>) -> Duration {
+        match estimate.and_then(|e| e.rto()) {
+            Some(rto) => rto.clamp(self.min_rto, self.max_rto.max(self.min_rto)),
+            None => self.initial_rto,
+        }
+    }
+
+    // How long to wait for a response after transmission `attempt`
+    // (0 based) before retransmitting or giving up.
+    pub fn wait(&self, attempt: u32, rto: Duration) -> Duration {
+        if attempt + 1 >= self.rc {
+            return rto * self.rm;
+        }
+        let doubled = rto.saturating_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX));
+        doubled.min(self.max_rto.max(rto))
+    }
+
+    // Deadline of a transaction first sent at `start`, from `total_timeout`.
+    pub fn deadline(&self, start: Instant) -> Option<Instant> {
+        self.total_timeout.map(|t| start + t)
+    }
+
+    // When transmission `attempt`, sent at `sent`, stops waiting for a
+    // response: its wait, cut short by the transaction's deadline.
+    pub fn timeout_at(
+        &self,
+        attempt: u32,
+        rto: Duration,
+        sent: Instant,
+        deadline: Option<Instant>,
+    ) -> Instant {
+        let timeout = sent + self.wait(attempt, rto);
+        match deadline {
+            Some(deadline) => timeout.min(deadline),
+            None => timeout,
+        }
+    }
+
+    // Whether another transmission may follow transmission `attempt` at
+    // `now`, or the transaction has timed out.
+    pub fn may_retransmit(&self, attempt: u32, now: Instant, deadline: Option<Instant>) -> bool {
+        attempt + 1 < self.rc && deadline.is_none_or(|d| now < d)
+    }
+}
+
+// Smoothed round-trip time of one server (RFC 6298).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RttEstimate {
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+}
+
+// Clock granularity G of RFC 6298.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+impl RttEstimate {
+    pub fn update(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + sample / 8);
+            }
+        }
+    }
+
+    // RTO = SRTT + max(G, 4 * RTTVAR), None before the first sample.
+    pub fn rto(&self) -> Option<Duration> {
+        self.srtt
+            .map(|srtt| srtt + (self.rttvar * 4).max(CLOCK_GRANULARITY))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total(policy: &RetransmitPolicy, rto: Duration) -> Duration {
+        (0..policy.rc).map(|i| policy.wait(i, rto)).sum()
+    }
+
+    #[test]
+    fn default_schedule_test() {
+        let policy = RetransmitPolicy::default();
+        let rto = policy.rto(None);
+        assert_eq!(policy.wait(0, rto), Duration::from_millis(100));
+        assert_eq!(policy.wait(4, rto), Duration::from_millis(1600));
+        assert_eq!(policy.wait(6, rto), Duration::from_millis(1600));
+        assert_eq!(total(&policy, rto), Duration::from_millis(9500));
+    }
+
+    #[test]
+    fn rfc5389_schedule_test() {
+        let policy = RetransmitPolicy::rfc5389();
+        let rto = policy.rto(None);
+        assert_eq!(policy.wait(1, rto), Duration::from_millis(1000));
+        assert_eq!(policy.wait(6, rto), Duration::from_millis(8000));
+        assert_eq!(total(&policy, rto), Duration::from_millis(39500));
+    }
+
+    #[test]
+    fn timeout_test() {
+        let policy = RetransmitPolicy {
+            rc: 3,
+            total_timeout: Some(Duration::from_millis(250)),
+            ..RetransmitPolicy::default()
+        };
+        let start = Instant::now();
+        let deadline = policy.deadline(start);
+        let rto = policy.rto(None);
+        assert_eq!(
+            policy.timeout_at(0, rto, start, deadline),
+            start + Duration::from_millis(100)
+        );
+        // The last wait (16 * RTO) is cut short by the deadline.
+        assert_eq!(
+            policy.timeout_at(2, rto, start, deadline),
+            deadline.unwrap()
+        );
+
+        assert!(policy.may_retransmit(1, start, deadline));
+        assert!(!policy.may_retransmit(2, start, deadline));
+        assert!(!policy.may_retransmit(0, deadline.unwrap(), deadline));
+    }
+
+    #[test]
+    fn rtt_estimate_test() {
+        let mut estimate = RttEstimate::default();
+        assert_eq!(estimate.rto(), None);
+
+        estimate.update(Duration::from_millis(40));
+        assert_eq!(estimate.srtt, Some(Duration::from_millis(40)));
+        assert_eq!(estimate.rttvar, Duration::from_millis(20));
+        assert_eq!(estimate.rto(), Some(Duration::from_millis(120)));
+
+        estimate.update(Duration::from_millis(80));
+        assert_eq!(estimate.srtt, Some(Duration::from_millis(45)));
+        assert_eq!(estimate.rttvar, Duration::from_millis(25));
+
+        let policy = RetransmitPolicy::default();
+        assert_eq!(policy.rto(Some(&estimate)), Duration::from_millis(145));
+    }
+}
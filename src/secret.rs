@@ -0,0 +1,237 @@
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::attribute::message_integrity;
+use crate::utils::align;
+use crate::{
+    Attribute, DecodeMode, Packet, Version, ATTRIBUTE_ERROR_CODE, ATTRIBUTE_MESSAGE_INTEGRITY,
+    ATTRIBUTE_PASSWORD, ATTRIBUTE_USERNAME, TYPE_SHARED_ERROR_RESPONSE, TYPE_SHARED_SECRET_REQUEST,
+    TYPE_SHARED_SECRET_RESPONSE,
+};
+
+use super::Client;
+
+// Shared secret of RFC 3489 section 8.2: a USERNAME and PASSWORD obtained
+// from the server, then used to sign Binding requests with
+// MESSAGE-INTEGRITY.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+// Runs the Shared Secret exchange over `stream`.
+//
+// `stream` must be a TLS session already established and authenticated with
+// the server (RFC 3489 section 9.2). This crate doesn't do TLS itself: over a
+// plain TCP stream the password goes out in clear text and anyone on the path
+// can forge signed responses with it.
+pub fn request_shared_secret<S: Read + Write>(stream: &mut S) -> Result<Credentials, String> {
+    let mut req = Packet::new_classic();
+    req.types = TYPE_SHARED_SECRET_REQUEST;
+    stream
+        .write_all(&req.bytes())
+        .and_then(|_| stream.flush())
+        .map_err(|e| e.to_string())?;
+
+    let mut buf = vec![0u8; 20];
+    stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    let length = BigEndian::read_u16(&buf[2..4]) as usize;
+    buf.resize(20 + length, 0);
+    stream
+        .read_exact(&mut buf[20..])
+        .map_err(|e| e.to_string())?;
+
+    let resp = Packet::decode(&buf, DecodeMode::Lenient).map_err(|e| e.to_string())?;
+    if resp.trans_id != req.trans_id {
+        return Err("Server error: transaction id mismatch".to_string());
+    }
+    match resp.types {
+        TYPE_SHARED_SECRET_RESPONSE => {
+            let username = attribute_string(&resp, ATTRIBUTE_USERNAME)
+                .ok_or_else(|| "Server error: no USERNAME".to_string())?;
+            let password = attribute_string(&resp, ATTRIBUTE_PASSWORD)
+                .ok_or_else(|| "Server error: no PASSWORD".to_string())?;
+            Ok(Credentials { username, password })
+        }
+        TYPE_SHARED_ERROR_RESPONSE => Err(match error_code(&resp) {
+            Some((code, reason)) => format!("Server error: {} {}", code, reason),
+            None => "Server error: shared secret error response".to_string(),
+        }),
+        t => Err(format!("Server error: unexpected message type {:#06x}", t)),
+    }
+}
+
+// RFC 3489 pads USERNAME and PASSWORD to a multiple of 4 bytes. Trailing
+// zeros left by Attribute::new are not part of the value.
+fn attribute_string(pkt: &Packet, s_type: u16) -> Option<String> {
+    let a = pkt.attributes.iter().find(|a| a.s_type == s_type)?;
+    let value = match a.value.iter().rposition(|b| *b != 0) {
+        Some(end) => &a.value[..=end],
+        None => &a.value[..0],
+    };
+    String::from_utf8(value.to_vec()).ok()
+}
+
+//  0                   1                   2                   3
+//  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |                   0                     |Class|     Number    |
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+// |      Reason Phrase (variable)                                ..
+// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+pub fn error_code(pkt: &Packet) -> Option<(u16, String)> {
+    let a = pkt
+        .attributes
+        .iter()
+        .find(|a| a.s_type == ATTRIBUTE_ERROR_CODE)?;
+    if a.value.len() < 4 {
+        return None;
+    }
+    let code = (a.value[2] & 0x07) as u16 * 100 + a.value[3] as u16;
+    let reason = String::from_utf8_lossy(&a.value[4..])
+        .trim_end_matches('\0')
+        .to_string();
+    Some((code, reason))
+}
+
+impl Packet {
+    // Appends MESSAGE-INTEGRITY keyed with `key`; only FINGERPRINT may
+    // follow it.
+    pub fn add_message_integrity(&mut self, key: &[u8]) {
+        self.length += 24;
+        let attribute = Attribute::new_message_integrity_attribute(self, key);
+        self.length -= 24;
+        self.add_attribute(attribute);
+    }
+}
+
+// Checks the MESSAGE-INTEGRITY of a received message. Works on the raw
+// bytes, as re-encoding a Packet doesn't reproduce the sender's padding.
+pub fn check_message_integrity(buf: &[u8], key: &[u8]) -> Result<(), String> {
+    let pkt = Packet::decode(buf, DecodeMode::Lenient).map_err(|e| e.to_string())?;
+    let mut offset = 20;
+    for a in pkt.attributes.iter() {
+        if a.s_type == ATTRIBUTE_MESSAGE_INTEGRITY {
+            if a.value.len() != 20 {
+                return Err("MESSAGE-INTEGRITY malformed".to_string());
+            }
+            // The length in the header covers MESSAGE-INTEGRITY itself.
+            let mut text = buf[..offset].to_vec();
+            BigEndian::write_u16(&mut text[2..4], (offset - 20 + 24) as u16);
+            let classic = pkt.version() == Version::RFC3489;
+            if message_integrity(&text, key, classic)[..] != a.value[..] {
+                return Err("MESSAGE-INTEGRITY mismatch".to_string());
+            }
+            return Ok(());
+        }
+        let length = BigEndian::read_u16(&buf[offset + 2..offset + 4]);
+        offset += 4 + align(length) as usize;
+    }
+    Err("No MESSAGE-INTEGRITY".to_string())
+}
+
+impl Client {
+    // Gets credentials from the server and signs the following Binding
+    // requests with them; responses without a valid MESSAGE-INTEGRITY are
+    // discarded from then on. `stream` must be TLS, see
+    // request_shared_secret.
+    pub fn shared_secret<S: Read + Write>(
+        &mut self,
+        stream: &mut S,
+    ) -> Result<Credentials, String> {
+        let credentials = request_shared_secret(stream)?;
+        self.credentials = Some(credentials.clone());
+        Ok(credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::test_client;
+    use crate::TYPE_BINDING_REQUEST;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // Answers one Shared Secret Request with `attributes`.
+    fn server(types: u16, attributes: Vec<Attribute>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 20];
+            stream.read_exact(&mut buf).unwrap();
+            let req = Packet::decode(&buf, DecodeMode::Lenient).unwrap();
+            assert_eq!(req.types, TYPE_SHARED_SECRET_REQUEST);
+            assert_eq!(req.version(), Version::RFC3489);
+
+            let mut resp = Packet::new_classic();
+            resp.types = types;
+            resp.trans_id = req.trans_id;
+            for a in attributes {
+                resp.add_attribute(a);
+            }
+            stream.write_all(&resp.bytes()).unwrap();
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+
+    #[test]
+    fn shared_secret_test() {
+        let mut stream = server(
+            TYPE_SHARED_SECRET_RESPONSE,
+            vec![
+                Attribute::new(ATTRIBUTE_USERNAME, b"user"),
+                Attribute::new(ATTRIBUTE_PASSWORD, b"secret-password"),
+            ],
+        );
+        let mut client = test_client();
+        let credentials = client.shared_secret(&mut stream).unwrap();
+        assert_eq!(credentials, Credentials::new("user", "secret-password"));
+        assert_eq!(client.credentials, Some(credentials));
+
+        let req = client.new_bind_req(false, false, Vec::new());
+        let bytes = req.bytes();
+        check_message_integrity(&bytes, b"secret-password").unwrap();
+        assert!(check_message_integrity(&bytes, b"wrong").is_err());
+    }
+
+    #[test]
+    fn shared_secret_error_test() {
+        let mut stream = server(
+            TYPE_SHARED_ERROR_RESPONSE,
+            vec![Attribute::new(ATTRIBUTE_ERROR_CODE, b"\0\0\x04\x21Use TLS")],
+        );
+        assert_eq!(
+            request_shared_secret(&mut stream).unwrap_err(),
+            "Server error: 433 Use TLS"
+        );
+    }
+
+    #[test]
+    fn message_integrity_test() {
+        for mut pkt in [Packet::new(), Packet::new_classic()] {
+            pkt.types = TYPE_BINDING_REQUEST;
+            pkt.add_attribute(Attribute::new_username_attribute("user"));
+            pkt.add_message_integrity(b"key");
+            let mut bytes = pkt.bytes();
+            check_message_integrity(&bytes, b"key").unwrap();
+            bytes[25] ^= 1;
+            assert!(check_message_integrity(&bytes, b"key").is_err());
+        }
+    }
+}
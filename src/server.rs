@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+
+use tracing::{debug, trace};
+
+use crate::ecn::{enable_recv_ecn, recv_with_ecn, Ecn};
+use crate::secret::check_message_integrity;
+use crate::utils::to_hex;
+use crate::{
+    Attribute, Class, Credentials, DecodeMode, Method, Packet, Version, ATTRIBUTE_FINGERPRINT,
+    ATTRIBUTE_MAPPED_ADDRESS, ATTRIBUTE_PADDING, ATTRIBUTE_REFLECTED_FROM, ATTRIBUTE_RESPONSE_PORT,
+    ATTRIBUTE_SOURCE_ADDRESS, ATTRIBUTE_XOR_MAPPED_ADDRESS, TYPE_BINDING_RESPONSE,
+};
+
+const MAX_TRACKED_TRANSACTIONS: usize = 4096;
+
+// A Binding server on a single socket. It can't honour CHANGE-REQUEST and
+// doesn't advertise CHANGED-ADDRESS, so clients classify it accordingly.
+//
+// `classic` answers every request the RFC 3489 way: MAPPED-ADDRESS and
+// SOURCE-ADDRESS. Otherwise RFC 3489 requests (no magic cookie) only get the
+// MAPPED-ADDRESS of RFC 5389 section 12.2.
+//
+// RESPONSE-ADDRESS sends the response to a third party, which makes an open
+// server a reflector. It is only honoured with `response_address` set, or for
+// requests signed with `credentials`; other requests are answered at their
+// source.
+//
+// A request with PADDING gets a response padded to the request's size
+// (RFC 5780 section 7.8), so path MTU probes test both directions. Not with
+// RESPONSE-PORT, whose response goes out unpadded.
+pub struct Server {
+    pub conn: UdpSocket,
+    pub software_name: String,
+    pub classic: bool,
+    pub response_address: bool,
+    pub credentials: Option<Credentials>,
+    responses: Mutex<HashMap<[u8; 16], u8>>, // responses sent per transaction, for RFC 7982
+}
+
+impl Server {
+    pub fn bind(addr: &str, software_name: String) -> io::Result<Server> {
+        let conn = UdpSocket::bind(addr)?;
+        // Without it ECN-CHECK reports V = 0.
+        if let Err(e) = enable_recv_ecn(&conn) {
+            debug!(error = %e, "can't read the ECN field of requests");
+        }
+        Ok(Server {
+            conn,
+            software_name,
+            classic: false,
+            response_address: false,
+            credentials: None,
+            responses: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr()
+    }
+
+    // The response to a datagram and where to send it, None if the datagram
+    // isn't a Binding request.
+    pub fn response(&self, data: &[u8], from: SocketAddr) -> Option<(Packet, SocketAddr)> {
+        self.response_with_ecn(data, from, None)
+    }
+
+    // `ecn` is the ECN field the request arrived with, reported back in
+    // ECN-CHECK when the request asks for it.
+    pub fn response_with_ecn(
+        &self,
+        data: &[u8],
+        from: SocketAddr,
+        ecn: Option<Ecn>,
+    ) -> Option<(Packet, SocketAddr)> {
+        let req = Packet::decode(data, DecodeMode::Lenient).ok()?;
+        if req.class() != Class::Request || req.method() != Method::Binding {
+            return None;
+        }
+
+        let mut resp = Packet::new();
+        resp.types = TYPE_BINDING_RESPONSE;
+        resp.trans_id = req.trans_id;
+        let mut dest = from;
+
+        if !self.classic && req.version() == Version::RFC3489 {
+            resp.add_attribute(Attribute::new_addr_attribute(
+                ATTRIBUTE_MAPPED_ADDRESS,
+                &from,
+            ));
+            return Some((resp, dest));
+        }
+        if self.classic {
+            resp.add_attribute(Attribute::new_addr_attribute(
+                ATTRIBUTE_MAPPED_ADDRESS,
+                &from,
+            ));
+            if let Ok(local) = self.conn.local_addr() {
+                resp.add_attribute(Attribute::new_addr_attribute(
+                    ATTRIBUTE_SOURCE_ADDRESS,
+                    &local,
+                ));
+            }
+            let signed = self.signed(data);
+            let response_addr = req.get_response_addr().map(SocketAddr::from);
+            if let Some(response_addr) = response_addr {
+                if self.response_address || signed {
+                    resp.add_attribute(Attribute::new_addr_attribute(
+                        ATTRIBUTE_REFLECTED_FROM,
+                        &from,
+                    ));
+                    dest = response_addr;
+                } else {
+                    debug!(from = %from, to = %response_addr, "ignoring unauthenticated RESPONSE-ADDRESS");
+                }
+            }
+            // Clients holding the shared secret discard unsigned responses.
+            if let (true, Some(credentials)) = (signed, &self.credentials) {
+                resp.add_message_integrity(credentials.password.as_bytes());
+            }
+            return Some((resp, dest));
+        }
+
+        resp.add_attribute(Attribute::new_xor_addr_attribute(
+            ATTRIBUTE_XOR_MAPPED_ADDRESS,
+            &from,
+            &req.trans_id,
+        ));
+        if let Some((count, _)) = req.get_transmit_counter() {
+            let sent = self.count_response(req.trans_id);
+            resp.add_attribute(Attribute::new_transmit_counter_attribute(count, sent));
+        }
+        if req.has_ecn_check() {
+            resp.add_attribute(Attribute::new_ecn_check_attribute(ecn));
+        }
+        if !self.software_name.is_empty() {
+            resp.add_attribute(Attribute::new_software_attribute(&self.software_name));
+        }
+        let has = |t| req.attributes.iter().any(|a| a.s_type == t);
+        let fingerprint = has(ATTRIBUTE_FINGERPRINT);
+        if has(ATTRIBUTE_PADDING) && !has(ATTRIBUTE_RESPONSE_PORT) {
+            // Room left for the PADDING header and the FINGERPRINT.
+            let size = resp.bytes().len() + 4 + if fingerprint { 8 } else { 0 };
+            if let Some(len) = data.len().checked_sub(size) {
+                resp.add_attribute(Attribute::new_padding_attribute(len & !3));
+            }
+        }
+        if fingerprint {
+            resp.length += 8;
+            let fingerprint = Attribute::new_fingerprint_attribute(&resp);
+            resp.length -= 8;
+            resp.add_attribute(fingerprint);
+        }
+        Some((resp, dest))
+    }
+
+    // Whether the request carries a MESSAGE-INTEGRITY valid for
+    // `credentials`.
+    fn signed(&self, data: &[u8]) -> bool {
+        match &self.credentials {
+            Some(credentials) => {
+                check_message_integrity(data, credentials.password.as_bytes()).is_ok()
+            }
+            None => false,
+        }
+    }
+
+    // Counts a response to `trans_id`, including this one.
+    fn count_response(&self, trans_id: [u8; 16]) -> u8 {
+        let mut responses = match self.responses.lock() {
+            Ok(r) => r,
+            Err(_) => return 1,
+        };
+        // Transactions are short lived, forget them all now and then.
+        if responses.len() >= MAX_TRACKED_TRANSACTIONS && !responses.contains_key(&trans_id) {
+            responses.clear();
+        }
+        let count = responses.entry(trans_id).or_insert(0);
+        *count = count.saturating_add(1);
+        *count
+    }
+
+    // Answers one datagram.
+    pub fn serve_one(&self) -> io::Result<()> {
+        let mut buf = [0u8; 1500];
+        let (n, from, ecn) = recv_with_ecn(&self.conn, &mut buf)?;
+        match self.response_with_ecn(&buf[..n], from, ecn) {
+            Some((resp, dest)) => {
+                trace!(from = %from, to = %dest, trans_id = %to_hex(&resp.trans_id), "binding response");
+                self.conn.send_to(&resp.bytes(), dest)?;
+            }
+            None => debug!(from = %from, length = n, "ignoring datagram"),
+        }
+        Ok(())
+    }
+
+    pub fn serve(&self) -> io::Result<()> {
+        loop {
+            self.serve_one()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{test_client, test_client_builder};
+    use std::thread;
+    use std::time::Duration;
+
+    fn server(requests: usize) -> SocketAddr {
+        configured_server(requests, |_| {})
+    }
+
+    fn configured_server(requests: usize, configure: impl FnOnce(&mut Server)) -> SocketAddr {
+        let mut server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        configure(&mut server);
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            for _ in 0..requests {
+                server.serve_one().unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn binding_test() {
+        let server_addr = server(1);
+        let client = test_client();
+        let local = client.conn.local_addr().unwrap();
+        let resp = client
+            .send_bind_req(&client.conn, server_addr, false, false)
+            .unwrap();
+        assert_eq!(resp.packet.version(), Version::RFC5389);
+        assert!(resp.packet.get_xor_mapped_addr().is_some());
+        assert_eq!(resp.mapped_addr.unwrap().string(), local.to_string());
+        assert!(resp.source_addr.is_none());
+    }
+
+    #[test]
+    fn classic_binding_test() {
+        let server_addr = configured_server(1, |s| s.classic = true);
+        let client = test_client_builder(Duration::from_secs(2))
+            .classic(true)
+            .build()
+            .unwrap();
+        let local = client.conn.local_addr().unwrap();
+        let resp = client
+            .send_bind_req(&client.conn, server_addr, false, false)
+            .unwrap();
+        assert_eq!(resp.packet.version(), Version::RFC3489);
+        assert!(resp.packet.get_xor_mapped_addr().is_none());
+        assert_eq!(resp.mapped_addr.unwrap().string(), local.to_string());
+        assert_eq!(resp.source_addr.unwrap().string(), server_addr.to_string());
+    }
+
+    #[test]
+    fn response_address_test() {
+        let server_addr = configured_server(1, |s| {
+            s.classic = true;
+            s.response_address = true;
+        });
+        let client = test_client_builder(Duration::from_secs(2))
+            .classic(true)
+            .build()
+            .unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other_addr = other.local_addr().unwrap();
+
+        let req = client.new_bind_req_with_response_addr(false, false, &other_addr);
+        client.conn.send_to(&req.bytes(), server_addr).unwrap();
+
+        let mut buf = [0u8; 1500];
+        let (n, from) = other.recv_from(&mut buf).unwrap();
+        let resp = Packet::new_packet_form_bytes(buf[..n].to_vec()).unwrap();
+        assert_eq!(from, server_addr);
+        assert_eq!(resp.trans_id, req.trans_id);
+        assert_eq!(
+            resp.get_reflected_from().unwrap().string(),
+            client.conn.local_addr().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn padding_test() {
+        let client = test_client();
+        let from: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        for size in [548, 1400] {
+            let req = client.new_padded_bind_req(size).unwrap();
+            let (resp, _) = server.response(&req.bytes(), from).unwrap();
+            assert_eq!(resp.bytes().len(), size);
+            assert!(resp.get_xor_mapped_addr().is_some());
+        }
+
+        // Smaller than the response without padding, sent as is.
+        let req = client.new_padded_bind_req(40).unwrap();
+        let (resp, _) = server.response(&req.bytes(), from).unwrap();
+        assert!(!resp
+            .attributes
+            .iter()
+            .any(|a| a.s_type == ATTRIBUTE_PADDING));
+
+        // RESPONSE-PORT responses aren't padded.
+        let mut req = client.new_padded_bind_req(1400).unwrap();
+        req.add_attribute(Attribute::new_response_port_attribute(from.port()));
+        let (resp, _) = server.response(&req.bytes(), from).unwrap();
+        assert!(resp.bytes().len() < 100);
+    }
+
+    #[test]
+    fn unauthenticated_response_address_test() {
+        let mut client = test_client();
+        client.classic = true;
+        let from: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let victim: SocketAddr = "198.51.100.1:53".parse().unwrap();
+        let req = client.new_bind_req_with_response_addr(false, false, &victim);
+
+        // Neither the default server nor a classic one reflects to `victim`.
+        let mut server = Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let (resp, dest) = server.response(&req.bytes(), from).unwrap();
+        assert_eq!(dest, from);
+        assert!(resp.get_source_addr().is_none());
+        assert!(resp.get_reflected_from().is_none());
+
+        server.classic = true;
+        let (resp, dest) = server.response(&req.bytes(), from).unwrap();
+        assert_eq!(dest, from);
+        assert!(resp.get_reflected_from().is_none());
+
+        // A request signed with the shared secret may redirect the response.
+        server.credentials = Some(Credentials::new("user", "password"));
+        assert_eq!(server.response(&req.bytes(), from).unwrap().1, from);
+        client.credentials = server.credentials.clone();
+        let req = client.new_bind_req_with_response_addr(false, false, &victim);
+        assert_eq!(server.response(&req.bytes(), from).unwrap().1, victim);
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{Client, ConsensusResult, NAT};
+
+// A public STUN server and what it is known to support.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerEntry {
+    pub addr: String,
+    pub rfc5780: bool, // answers CHANGE-REQUEST and reports OTHER-ADDRESS
+    pub ipv4: bool,
+    pub ipv6: bool,
+}
+
+impl ServerEntry {
+    pub fn new(addr: &str, rfc5780: bool, ipv4: bool, ipv6: bool) -> ServerEntry {
+        ServerEntry {
+            addr: addr.to_string(),
+            rfc5780,
+            ipv4,
+            ipv6,
+        }
+    }
+}
+
+// (address, RFC 5780, IPv4, IPv6)
+const PUBLIC_SERVERS: [(&str, bool, bool, bool); 6] = [
+    ("stun.stunprotocol.org:3478", true, true, false),
+    ("stun.ekiga.net:3478", false, true, false),
+    ("stun.l.google.com:19302", false, true, true),
+    ("stun1.l.google.com:19302", false, true, true),
+    ("stun2.l.google.com:19302", false, true, true),
+    ("stun.cloudflare.com:3478", false, true, true),
+];
+
+// An ordered list of servers to choose from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerList {
+    pub entries: Vec<ServerEntry>,
+}
+
+impl ServerList {
+    // Uses `entries` instead of the built-in list.
+    pub fn new(entries: Vec<ServerEntry>) -> ServerList {
+        ServerList { entries }
+    }
+
+    pub fn builtin() -> ServerList {
+        ServerList {
+            entries: PUBLIC_SERVERS
+                .iter()
+                .map(|&(addr, rfc5780, ipv4, ipv6)| ServerEntry::new(addr, rfc5780, ipv4, ipv6))
+                .collect(),
+        }
+    }
+
+    // Servers reachable over the given family, optionally only those that
+    // support the RFC 5780 behavior tests.
+    pub fn select(&self, ipv6: bool, require_rfc5780: bool) -> ServerList {
+        ServerList {
+            entries: self
+                .entries
+                .iter()
+                .filter(|e| if ipv6 { e.ipv6 } else { e.ipv4 })
+                .filter(|e| e.rfc5780 || !require_rfc5780)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // Server addresses, healthiest first. Servers with equal scores keep
+    // their list order.
+    pub fn ranked(&self, health: &HealthStore) -> Vec<String> {
+        let mut entries: Vec<(&ServerEntry, f64)> = self
+            .entries
+            .iter()
+            .map(|e| (e, health.score(&e.addr)))
+            .collect();
+        entries.sort_by(|a, b| b.1.total_cmp(&a.1));
+        entries.into_iter().map(|(e, _)| e.addr.clone()).collect()
+    }
+}
+
+impl Default for ServerList {
+    fn default() -> Self {
+        ServerList::builtin()
+    }
+}
+
+// What we have observed of one server.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ServerHealth {
+    pub latency: Option<Duration>, // moving average of the RTT
+    pub successes: u32,
+    pub timeouts: u32,
+    pub wrong_responses: u32,
+}
+
+// Latency at which a server's score is halved.
+const LATENCY_SCALE_MS: f64 = 250.0;
+
+impl ServerHealth {
+    // Higher is better. Unknown servers score the same as a server with as
+    // many successes as failures and an average latency.
+    pub fn score(&self) -> f64 {
+        // Wrong answers are worse than silence: they cause misclassification.
+        let failures = self.timeouts as f64 + 2.0 * self.wrong_responses as f64;
+        let reliability = (self.successes as f64 + 1.0) / (self.successes as f64 + failures + 2.0);
+        let latency_ms = self
+            .latency
+            .map_or(LATENCY_SCALE_MS, |l| l.as_secs_f64() * 1000.0);
+        reliability / (1.0 + latency_ms / LATENCY_SCALE_MS)
+    }
+}
+
+// Health of every server we talked to, optionally backed by a small file
+// with one line per server:
+//     <addr> <latency µs or -> <successes> <timeouts> <wrong responses>
+// Latencies are written with a "us" suffix; bare numbers, from older
+// files, are milliseconds.
+#[derive(Debug, Clone, Default)]
+pub struct HealthStore {
+    pub path: Option<PathBuf>,
+    entries: HashMap<String, ServerHealth>,
+}
+
+impl HealthStore {
+    pub fn new() -> HealthStore {
+        HealthStore::default()
+    }
+
+    // Loads the store from `path`; a missing file gives an empty store.
+    // Malformed lines are skipped.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<HealthStore> {
+        let path = path.as_ref();
+        let mut store = HealthStore {
+            path: Some(path.to_path_buf()),
+            entries: HashMap::new(),
+        };
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+        for line in content.lines() {
+            if let Some((addr, health)) = parse_line(line) {
+                store.entries.insert(addr, health);
+            }
+        }
+        Ok(store)
+    }
+
+    // Writes the store back to the file it was loaded from.
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => self.save_to(path),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Health store has no path",
+            )),
+        }
+    }
+
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut addrs: Vec<&String> = self.entries.keys().collect();
+        addrs.sort();
+        let mut content = String::new();
+        for addr in addrs {
+            let h = &self.entries[addr];
+            let latency = h
+                .latency
+                .map_or("-".to_string(), |l| format!("{}us", l.as_micros()));
+            content.push_str(&format!(
+                "{} {} {} {} {}\n",
+                addr, latency, h.successes, h.timeouts, h.wrong_responses
+            ));
+        }
+        // Write then rename so a crash never leaves a truncated file.
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn get(&self, addr: &str) -> Option<&ServerHealth> {
+        self.entries.get(addr)
+    }
+
+    pub fn score(&self, addr: &str) -> f64 {
+        self.entries.get(addr).copied().unwrap_or_default().score()
+    }
+
+    pub fn record_success(&mut self, addr: &str, rtt: Option<Duration>) {
+        let h = self.entries.entry(addr.to_string()).or_default();
+        h.successes = h.successes.saturating_add(1);
+        if let Some(rtt) = rtt {
+            h.latency = Some(match h.latency {
+                Some(l) => l * 7 / 8 + rtt / 8,
+                None => rtt,
+            });
+        }
+    }
+
+    pub fn record_timeout(&mut self, addr: &str) {
+        let h = self.entries.entry(addr.to_string()).or_default();
+        h.timeouts = h.timeouts.saturating_add(1);
+    }
+
+    pub fn record_wrong_response(&mut self, addr: &str) {
+        let h = self.entries.entry(addr.to_string()).or_default();
+        h.wrong_responses = h.wrong_responses.saturating_add(1);
+    }
+}
+
+impl Client {
+    // A copy of the health store given to ClientBuilder::server_list, with
+    // what this client has seen since. Save it to rank the servers next
+    // time.
+    pub fn health(&self) -> Option<HealthStore> {
+        self.health.as_ref()?.lock().ok().map(|h| h.clone())
+    }
+
+    fn record_health(&self, addr: SocketAddr, record: impl FnOnce(&mut HealthStore, &str)) {
+        let health = match &self.health {
+            Some(health) => health,
+            None => return,
+        };
+        let name = match self.server_names.lock() {
+            Ok(names) => names.get(&addr).cloned(),
+            Err(_) => None,
+        };
+        if let (Some(name), Ok(mut health)) = (name, health.lock()) {
+            record(&mut health, &name);
+        }
+    }
+
+    pub(crate) fn record_success(&self, addr: SocketAddr, rtt: Option<Duration>) {
+        self.record_health(addr, |h, name| h.record_success(name, rtt));
+    }
+
+    pub(crate) fn record_timeout(&self, addr: SocketAddr) {
+        self.record_health(addr, |h, name| h.record_timeout(name));
+    }
+
+    // Books the servers of a consensus run with an error verdict or a vote
+    // against the consensus as having given a wrong response. Answers and
+    // timeouts were booked request by request.
+    pub(crate) fn record_wrong_responses(&self, consensus: &ConsensusResult) {
+        let health = match &self.health {
+            Some(health) => health,
+            None => return,
+        };
+        if let Ok(mut health) = health.lock() {
+            for r in consensus.results.iter() {
+                let wrong = match r.nat {
+                    NAT::NATBlocked => false,
+                    NAT::NATError => true,
+                    nat => nat.canonical() != consensus.nat,
+                };
+                if wrong {
+                    health.record_wrong_response(&r.server);
+                }
+            }
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, ServerHealth)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 5 {
+        return None;
+    }
+    let latency = match fields[1] {
+        "-" => None,
+        l => match l.strip_suffix("us") {
+            Some(us) => Some(Duration::from_micros(us.parse().ok()?)),
+            None => Some(Duration::from_millis(l.parse().ok()?)),
+        },
+    };
+    Some((
+        fields[0].to_string(),
+        ServerHealth {
+            latency,
+            successes: fields[2].parse().ok()?,
+            timeouts: fields[3].parse().ok()?,
+            wrong_responses: fields[4].parse().ok()?,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_test() {
+        let list = ServerList::builtin();
+        let v6 = list.select(true, false);
+        assert!(!v6.entries.is_empty());
+        assert!(v6.entries.iter().all(|e| e.ipv6));
+        assert!(list.select(false, true).entries.iter().all(|e| e.rfc5780));
+    }
+
+    #[test]
+    fn ranked_test() {
+        let list = ServerList::new(vec![
+            ServerEntry::new("a:3478", false, true, false),
+            ServerEntry::new("b:3478", false, true, false),
+            ServerEntry::new("c:3478", false, true, false),
+        ]);
+        let mut health = HealthStore::new();
+        health.record_timeout("a:3478");
+        health.record_success("c:3478", Some(Duration::from_millis(20)));
+        assert_eq!(list.ranked(&health), vec!["c:3478", "b:3478", "a:3478"]);
+
+        // Wrong answers rank below silence.
+        health.record_wrong_response("b:3478");
+        assert_eq!(list.ranked(&health), vec!["c:3478", "a:3478", "b:3478"]);
+    }
+
+    #[test]
+    fn save_load_test() {
+        let path = std::env::temp_dir().join(format!("stun-health-{}.txt", std::process::id()));
+        let mut health = HealthStore::new();
+        health.record_success("a:3478", Some(Duration::from_millis(30)));
+        health.record_timeout("b:3478");
+        health.save_to(&path).unwrap();
+
+        let loaded = HealthStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get("a:3478"), health.get("a:3478"));
+        assert_eq!(loaded.get("b:3478"), health.get("b:3478"));
+        assert_eq!(loaded.path, Some(path.clone()));
+
+        let missing = HealthStore::load(&path).unwrap();
+        assert!(missing.get("a:3478").is_none());
+    }
+
+    #[test]
+    fn latency_precision_test() {
+        let mut health = HealthStore::new();
+        health.record_success("lan:3478", Some(Duration::from_micros(350)));
+        let path = std::env::temp_dir().join(format!("stun-latency-{}.txt", std::process::id()));
+        health.save_to(&path).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let loaded = HealthStore::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content, "lan:3478 350us 1 0 0\n");
+        assert_eq!(
+            loaded.get("lan:3478").unwrap().latency,
+            Some(Duration::from_micros(350))
+        );
+
+        // Older files store milliseconds.
+        let (_, h) = parse_line("old:3478 12 1 0 0").unwrap();
+        assert_eq!(h.latency, Some(Duration::from_millis(12)));
+    }
+
+    #[test]
+    fn wrong_responses_test() {
+        let list = ServerList::new(
+            ["a:3478", "b:3478", "c:3478", "d:3478", "e:3478"]
+                .iter()
+                .map(|a| ServerEntry::new(a, false, true, false))
+                .collect(),
+        );
+        let client = crate::sim::test_client_builder(Duration::from_millis(200))
+            .server_list(&list, &HealthStore::new())
+            .build()
+            .unwrap();
+        let result = |server: &str, nat| crate::ServerResult {
+            server: server.to_string(),
+            nat,
+            mapped_addr: Err("-".to_string()),
+            rtt: None,
+        };
+        let consensus = ConsensusResult::from_results(vec![
+            result("a:3478", NAT::NATFull),
+            result("b:3478", NAT::NATFull),
+            result("c:3478", NAT::NATSymmetric),
+            result("d:3478", NAT::NATError),
+            result("e:3478", NAT::NATBlocked),
+        ]);
+        client.record_wrong_responses(&consensus);
+
+        let health = client.health().unwrap();
+        let wrong = |server| health.get(server).map_or(0, |h| h.wrong_responses);
+        assert_eq!(
+            ["a:3478", "b:3478", "c:3478", "d:3478", "e:3478"].map(wrong),
+            [0, 0, 1, 1, 0]
+        );
+    }
+
+    #[test]
+    fn client_health_test() {
+        let server = crate::Server::bind("127.0.0.1:0", "stun".to_string()).unwrap();
+        let server_addr = server.local_addr().unwrap().to_string();
+        std::thread::spawn(move || server.serve());
+        // Nobody answers there.
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap().to_string();
+
+        let list = ServerList::new(vec![
+            ServerEntry::new(&silent_addr, false, true, false),
+            ServerEntry::new(&server_addr, false, true, false),
+        ]);
+        let mut health = HealthStore::new();
+        health.record_timeout(&silent_addr);
+        let client = crate::sim::test_client_builder(Duration::from_millis(200))
+            .server_list(&list, &health)
+            .build()
+            .unwrap();
+        // The server known to time out goes last.
+        assert_eq!(
+            client.servers(),
+            vec![server_addr.clone(), silent_addr.clone()]
+        );
+
+        for server in client.servers() {
+            let addr = client.resolve_server(&server).unwrap();
+            let _ = client.send_bind_req(&client.conn, addr, false, false);
+            // CHANGE-REQUEST goes unanswered by this server, it isn't held
+            // against it.
+            let _ = client.send_bind_req(&client.conn, addr, true, true);
+        }
+        let health = client.health().unwrap();
+        let answered = health.get(&server_addr).unwrap();
+        assert_eq!((answered.successes, answered.timeouts), (1, 0));
+        assert!(answered.latency.is_some());
+        assert_eq!(health.get(&silent_addr).unwrap().timeouts, 2);
+    }
+}
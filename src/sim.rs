@@ -0,0 +1,633 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{
+    Attribute, Behavior, Class, DecodeMode, Method, Packet, Transport, Version,
+    ATTRIBUTE_CHANGED_ADDRESS, ATTRIBUTE_CHANGE_REQUEST, ATTRIBUTE_MAPPED_ADDRESS,
+    ATTRIBUTE_OTHER_ADDRESS, ATTRIBUTE_SOURCE_ADDRESS, ATTRIBUTE_XOR_MAPPED_ADDRESS,
+    TYPE_BINDING_RESPONSE,
+};
+
+// In-process network for deterministic tests: simulated sockets, a NAT in
+// front of the private ones and STUN servers, no real traffic.
+//
+// Delivery is synchronous. A datagram lands in the destination queue (or
+// is answered by a server, or dropped) before send_to returns, so an empty
+// queue means nothing is on the way and recv_from times out right away.
+// Time only moves with Network::advance.
+
+const FIRST_EXTERNAL_PORT: u16 = 40000;
+
+// NAT between the private sockets and everything else (RFC 4787).
+#[derive(Debug, Clone)]
+pub struct NatConfig {
+    pub mapping: Behavior,
+    pub filtering: Behavior,
+    pub public_ip: IpAddr,
+    pub translate: bool, // false: a firewall, addresses are kept but inbound traffic is filtered
+    pub hairpin: bool,   // private sockets can reach each other through their mapped addresses
+    pub mapping_lifetime: Duration, // idle time after which a mapping is forgotten
+    pub blocked: bool,   // drops all UDP
+}
+
+impl NatConfig {
+    pub fn new(mapping: Behavior, filtering: Behavior) -> NatConfig {
+        NatConfig {
+            mapping,
+            filtering,
+            public_ip: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1)),
+            translate: true,
+            hairpin: false,
+            mapping_lifetime: Duration::from_secs(120),
+            blocked: false,
+        }
+    }
+
+    pub fn full_cone() -> NatConfig {
+        NatConfig::new(
+            Behavior::BehaviorTypeEndpoint,
+            Behavior::BehaviorTypeEndpoint,
+        )
+    }
+
+    pub fn restricted() -> NatConfig {
+        NatConfig::new(Behavior::BehaviorTypeEndpoint, Behavior::BehaviorTypeAddr)
+    }
+
+    pub fn port_restricted() -> NatConfig {
+        NatConfig::new(
+            Behavior::BehaviorTypeEndpoint,
+            Behavior::BehaviorTypeAddrAndPort,
+        )
+    }
+
+    pub fn symmetric() -> NatConfig {
+        NatConfig::new(
+            Behavior::BehaviorTypeAddrAndPort,
+            Behavior::BehaviorTypeAddrAndPort,
+        )
+    }
+
+    // No translation, unsolicited traffic dropped.
+    pub fn firewall() -> NatConfig {
+        NatConfig {
+            translate: false,
+            ..NatConfig::port_restricted()
+        }
+    }
+
+    pub fn blocked() -> NatConfig {
+        NatConfig {
+            blocked: true,
+            ..NatConfig::full_cone()
+        }
+    }
+
+    // The part of the destination a mapping depends on.
+    fn mapping_key(&self, dest: SocketAddr) -> Option<SocketAddr> {
+        match self.mapping {
+            Behavior::BehaviorTypeEndpoint => None,
+            Behavior::BehaviorTypeAddr => Some(SocketAddr::new(dest.ip(), 0)),
+            _ => Some(dest),
+        }
+    }
+
+    fn allows(&self, permissions: &HashSet<SocketAddr>, from: SocketAddr) -> bool {
+        match self.filtering {
+            Behavior::BehaviorTypeEndpoint => true,
+            Behavior::BehaviorTypeAddr => permissions.iter().any(|p| p.ip() == from.ip()),
+            _ => permissions.contains(&from),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Mapping {
+    internal: SocketAddr,
+    key: Option<SocketAddr>,
+    external: SocketAddr,
+    permissions: HashSet<SocketAddr>, // destinations the internal socket sent to
+    last_used: Duration,
+}
+
+// A STUN server listening on its primary and alternate IP and port, the
+// four addresses of RFC 5780 section 4.
+#[derive(Debug, Clone)]
+pub struct SimServer {
+    pub primary: SocketAddr,
+    pub alternate: SocketAddr,
+    pub other_address: bool,  // advertises CHANGED-ADDRESS / OTHER-ADDRESS
+    pub change_request: bool, // honours CHANGE-REQUEST
+    // Answers requests sent to its alternate IP.
+    pub alternate_reachable: bool,
+}
+
+impl SimServer {
+    pub fn new(primary: SocketAddr, alternate: SocketAddr) -> SimServer {
+        SimServer {
+            primary,
+            alternate,
+            other_address: true,
+            change_request: true,
+            alternate_reachable: true,
+        }
+    }
+
+    pub fn addresses(&self) -> [SocketAddr; 4] {
+        let (ip1, ip2) = (self.primary.ip(), self.alternate.ip());
+        let (port1, port2) = (self.primary.port(), self.alternate.port());
+        [
+            SocketAddr::new(ip1, port1),
+            SocketAddr::new(ip1, port2),
+            SocketAddr::new(ip2, port1),
+            SocketAddr::new(ip2, port2),
+        ]
+    }
+
+    // The response to a request received on `local` and the address it is
+    // sent from.
+    pub fn response(
+        &self,
+        data: &[u8],
+        local: SocketAddr,
+        from: SocketAddr,
+    ) -> Option<(Packet, SocketAddr)> {
+        let req = Packet::decode(data, DecodeMode::Lenient).ok()?;
+        if req.class() != Class::Request || req.method() != Method::Binding {
+            return None;
+        }
+
+        let (mut change_ip, mut change_port) = (false, false);
+        if let Some(a) = req
+            .attributes
+            .iter()
+            .find(|a| a.s_type == ATTRIBUTE_CHANGE_REQUEST)
+        {
+            let flags = a.value.get(3).copied().unwrap_or(0);
+            change_ip = self.change_request && flags & 0x04 != 0;
+            change_port = self.change_request && flags & 0x02 != 0;
+        }
+        let source = SocketAddr::new(
+            if change_ip {
+                self.flip_ip(local.ip())
+            } else {
+                local.ip()
+            },
+            if change_port {
+                self.flip_port(local.port())
+            } else {
+                local.port()
+            },
+        );
+
+        let mut resp = Packet::new();
+        resp.types = TYPE_BINDING_RESPONSE;
+        resp.trans_id = req.trans_id;
+        resp.add_attribute(Attribute::new_addr_attribute(
+            ATTRIBUTE_MAPPED_ADDRESS,
+            &from,
+        ));
+        resp.add_attribute(Attribute::new_addr_attribute(
+            ATTRIBUTE_SOURCE_ADDRESS,
+            &source,
+        ));
+        let other = SocketAddr::new(self.flip_ip(local.ip()), self.flip_port(local.port()));
+        if self.other_address {
+            resp.add_attribute(Attribute::new_addr_attribute(
+                ATTRIBUTE_CHANGED_ADDRESS,
+                &other,
+            ));
+        }
+        if req.version() == Version::RFC5389 {
+            resp.add_attribute(Attribute::new_xor_addr_attribute(
+                ATTRIBUTE_XOR_MAPPED_ADDRESS,
+                &from,
+                &req.trans_id,
+            ));
+            if self.other_address {
+                resp.add_attribute(Attribute::new_addr_attribute(
+                    ATTRIBUTE_OTHER_ADDRESS,
+                    &other,
+                ));
+            }
+        }
+        Some((resp, source))
+    }
+
+    fn flip_ip(&self, ip: IpAddr) -> IpAddr {
+        if ip == self.primary.ip() {
+            self.alternate.ip()
+        } else {
+            self.primary.ip()
+        }
+    }
+
+    fn flip_port(&self, port: u16) -> u16 {
+        if port == self.primary.port() {
+            self.alternate.port()
+        } else {
+            self.primary.port()
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queues: HashMap<SocketAddr, VecDeque<(Vec<u8>, SocketAddr)>>,
+    private: HashSet<SocketAddr>,
+    servers: Vec<SimServer>,
+    nat: Option<NatConfig>,
+    mappings: Vec<Mapping>,
+    next_port: u16,
+    now: Duration,
+}
+
+impl State {
+    fn expire(&mut self) {
+        if let Some(nat) = &self.nat {
+            let (now, lifetime) = (self.now, nat.mapping_lifetime);
+            self.mappings.retain(|m| now - m.last_used < lifetime);
+        }
+    }
+
+    // Mapping used for a datagram from `internal` to `dest`, created if
+    // needed.
+    fn outbound(&mut self, internal: SocketAddr, dest: SocketAddr) -> SocketAddr {
+        let nat = match &self.nat {
+            Some(nat) => nat.clone(),
+            None => return internal,
+        };
+        let key = nat.mapping_key(dest);
+        let now = self.now;
+        let index = match self
+            .mappings
+            .iter()
+            .position(|m| m.internal == internal && m.key == key)
+        {
+            Some(i) => i,
+            None => {
+                let external = if nat.translate {
+                    let port = self.next_port;
+                    self.next_port = self.next_port.wrapping_add(1).max(FIRST_EXTERNAL_PORT);
+                    SocketAddr::new(nat.public_ip, port)
+                } else {
+                    internal
+                };
+                self.mappings.push(Mapping {
+                    internal,
+                    key,
+                    external,
+                    permissions: HashSet::new(),
+                    last_used: now,
+                });
+                self.mappings.len() - 1
+            }
+        };
+        let mapping = &mut self.mappings[index];
+        mapping.permissions.insert(dest);
+        mapping.last_used = now;
+        mapping.external
+    }
+
+    // Internal address for a datagram from `from` to the external address
+    // `to`, None if there is no mapping or filtering drops it.
+    fn inbound(&self, from: SocketAddr, to: SocketAddr) -> Option<SocketAddr> {
+        let nat = self.nat.as_ref()?;
+        let internal = self.mappings.iter().find(|m| m.external == to)?.internal;
+        // Filtering looks at every mapping sharing the external address.
+        let permissions: HashSet<SocketAddr> = self
+            .mappings
+            .iter()
+            .filter(|m| m.external == to)
+            .flat_map(|m| m.permissions.iter().copied())
+            .collect();
+        if nat.allows(&permissions, from) {
+            Some(internal)
+        } else {
+            None
+        }
+    }
+
+    fn is_external(&self, addr: SocketAddr) -> bool {
+        match &self.nat {
+            Some(nat) if nat.translate => addr.ip() == nat.public_ip,
+            Some(_) => self.private.contains(&addr),
+            None => false,
+        }
+    }
+
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, data: Vec<u8>) {
+        self.expire();
+        let nat = match &self.nat {
+            Some(nat) if self.private.contains(&from) => nat.clone(),
+            _ => return self.arrive(from, to, data),
+        };
+        if nat.blocked {
+            return;
+        }
+        if self.private.contains(&to) {
+            // Same side of the NAT.
+            self.push(from, to, data);
+            return;
+        }
+        if nat.translate && to.ip() == nat.public_ip && !nat.hairpin {
+            return;
+        }
+        let external = self.outbound(from, to);
+        self.arrive(external, to, data);
+    }
+
+    // A datagram from `from` reaching `to` on the public side.
+    fn arrive(&mut self, from: SocketAddr, to: SocketAddr, data: Vec<u8>) {
+        if let Some(server) = self.servers.iter().find(|s| s.addresses().contains(&to)) {
+            if !server.alternate_reachable && to.ip() == server.alternate.ip() {
+                return;
+            }
+            if let Some((resp, source)) = server.response(&data, to, from) {
+                self.arrive(source, from, resp.bytes());
+            }
+            return;
+        }
+        if self.is_external(to) {
+            if let Some(internal) = self.inbound(from, to) {
+                self.push(from, internal, data);
+            }
+            return;
+        }
+        self.push(from, to, data);
+    }
+
+    fn push(&mut self, from: SocketAddr, to: SocketAddr, data: Vec<u8>) {
+        if let Some(queue) = self.queues.get_mut(&to) {
+            queue.push_back((data, from));
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Network {
+    state: Arc<Mutex<State>>,
+}
+
+impl Network {
+    // A network without a NAT: every socket is reachable.
+    pub fn new() -> Network {
+        Network::default()
+    }
+
+    // Private sockets sit behind `nat`.
+    pub fn with_nat(nat: NatConfig) -> Network {
+        let network = Network::default();
+        {
+            let mut state = network.lock();
+            state.nat = Some(nat);
+            state.next_port = FIRST_EXTERNAL_PORT;
+        }
+        network
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A test that panicked holding the lock doesn't make the state any
+        // less usable.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // A socket on the public side.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<SimSocket> {
+        self.bind_socket(addr, false)
+    }
+
+    // A socket behind the NAT.
+    pub fn bind_private(&self, addr: SocketAddr) -> io::Result<SimSocket> {
+        self.bind_socket(addr, true)
+    }
+
+    fn bind_socket(&self, addr: SocketAddr, private: bool) -> io::Result<SimSocket> {
+        let mut state = self.lock();
+        if state.queues.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} already bound", addr),
+            ));
+        }
+        state.queues.insert(addr, VecDeque::new());
+        if private {
+            state.private.insert(addr);
+        }
+        Ok(SimSocket {
+            network: self.clone(),
+            addr,
+        })
+    }
+
+    pub fn add_server(&self, server: SimServer) {
+        self.lock().servers.push(server);
+    }
+
+    // Moves the simulated clock, expiring idle NAT mappings.
+    pub fn advance(&self, d: Duration) {
+        let mut state = self.lock();
+        state.now += d;
+        state.expire();
+    }
+
+    // External address currently mapped for `internal`, if any.
+    pub fn mapped_addrs(&self, internal: SocketAddr) -> Vec<SocketAddr> {
+        let mut state = self.lock();
+        state.expire();
+        state
+            .mappings
+            .iter()
+            .filter(|m| m.internal == internal)
+            .map(|m| m.external)
+            .collect()
+    }
+}
+
+pub struct SimSocket {
+    network: Network,
+    addr: SocketAddr,
+}
+
+impl Transport for SimSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.network.lock().send(self.addr, addr, buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut state = self.network.lock();
+        let (data, from) = state
+            .queues
+            .get_mut(&self.addr)
+            .and_then(|q| q.pop_front())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "no datagram"))?;
+        // Like a real socket, the rest of a long datagram is lost.
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok((n, from))
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        let mut state = self.network.lock();
+        state.queues.remove(&self.addr);
+        state.private.remove(&self.addr);
+    }
+}
+
+// The client the tests use: an ephemeral loopback port, SOFTWARE "stun"
+// and requests giving up after `total_timeout`.
+#[cfg(test)]
+pub(crate) fn test_client_builder(total_timeout: Duration) -> crate::ClientBuilder {
+    crate::Client::builder()
+        .bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+        .software("stun")
+        .retransmit_policy(crate::RetransmitPolicy {
+            total_timeout: Some(total_timeout),
+            ..crate::RetransmitPolicy::default()
+        })
+}
+
+// A test client whose requests give up after 2s.
+#[cfg(test)]
+pub(crate) fn test_client() -> crate::Client {
+    test_client_builder(Duration::from_secs(2)).build().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{NATBehavior, NAT};
+
+    const BEHAVIORS: [Behavior; 3] = [
+        Behavior::BehaviorTypeEndpoint,
+        Behavior::BehaviorTypeAddr,
+        Behavior::BehaviorTypeAddrAndPort,
+    ];
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn setup(nat: NatConfig) -> (Network, SimSocket, SocketAddr) {
+        let network = Network::with_nat(nat);
+        let server = SimServer::new(addr("198.51.100.1:3478"), addr("198.51.100.2:3479"));
+        network.add_server(server.clone());
+        let socket = network.bind_private(addr("192.168.1.10:5000")).unwrap();
+        (network, socket, server.primary)
+    }
+
+    #[test]
+    fn behavior_discover_test() {
+        let client = test_client();
+        for mapping in BEHAVIORS {
+            for filtering in BEHAVIORS {
+                let (_network, socket, server) = setup(NatConfig::new(mapping, filtering));
+                assert_eq!(
+                    client.behavior_discover(&socket, server),
+                    Ok(NATBehavior::new(mapping, filtering)),
+                    "mapping {}, filtering {}",
+                    mapping,
+                    filtering
+                );
+            }
+        }
+
+        // Without translation the mapping never changes.
+        let (_network, socket, server) = setup(NatConfig::firewall());
+        assert_eq!(
+            client.mapping_behavior(&socket, server),
+            Ok(Behavior::BehaviorTypeEndpoint)
+        );
+        let resp = client.test(&socket, server).unwrap();
+        assert_eq!(resp.mapped_addr.unwrap().string(), "192.168.1.10:5000");
+    }
+
+    #[test]
+    fn blocked_test() {
+        let client = test_client();
+        let (_network, socket, server) = setup(NatConfig::blocked());
+        assert_eq!(client.test(&socket, server).unwrap_err(), "NAT BLOCKED");
+    }
+
+    #[test]
+    fn no_other_address_test() {
+        let client = test_client();
+        let network = Network::with_nat(NatConfig::full_cone());
+        let mut server = SimServer::new(addr("198.51.100.1:3478"), addr("198.51.100.2:3479"));
+        server.other_address = false;
+        network.add_server(server.clone());
+        let socket = network.bind_private(addr("192.168.1.10:5000")).unwrap();
+
+        assert_eq!(
+            client.mapping_behavior(&socket, server.primary),
+            Err("Server error: no other address".to_string())
+        );
+        let report = client.discover_with(&socket, server.primary).report;
+        assert_eq!(report.nat, NAT::NATError);
+        assert_eq!(
+            report.result().unwrap_err(),
+            "Server error: no changed address"
+        );
+    }
+
+    #[test]
+    fn mapping_lifetime_test() {
+        let client = test_client();
+        let mut nat = NatConfig::full_cone();
+        nat.mapping_lifetime = Duration::from_secs(30);
+        let (network, socket, server) = setup(nat);
+
+        let first = client.test(&socket, server).unwrap().mapped_addr.unwrap();
+        network.advance(Duration::from_secs(20));
+        let second = client.test(&socket, server).unwrap().mapped_addr.unwrap();
+        assert_eq!(first, second);
+
+        network.advance(Duration::from_secs(30));
+        assert!(network.mapped_addrs(addr("192.168.1.10:5000")).is_empty());
+        let third = client.test(&socket, server).unwrap().mapped_addr.unwrap();
+        assert_ne!(first, third);
+    }
+
+    #[test]
+    fn hairpin_test() {
+        for hairpin in [false, true] {
+            let mut nat = NatConfig::full_cone();
+            nat.hairpin = hairpin;
+            let network = Network::with_nat(nat);
+            let a = network.bind_private(addr("192.168.1.10:5000")).unwrap();
+            let b = network.bind_private(addr("192.168.1.11:5000")).unwrap();
+            let outside = network.bind(addr("198.51.100.9:9000")).unwrap();
+
+            // Open mappings for both.
+            a.send_to(b"a", outside.local_addr().unwrap()).unwrap();
+            b.send_to(b"b", outside.local_addr().unwrap()).unwrap();
+            let mut buf = [0u8; 16];
+            let (_, a_mapped) = outside.recv_from(&mut buf).unwrap();
+            let (_, b_mapped) = outside.recv_from(&mut buf).unwrap();
+
+            a.send_to(b"hello", b_mapped).unwrap();
+            match b.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    assert!(hairpin);
+                    assert_eq!(&buf[..n], b"hello");
+                    assert_eq!(from, a_mapped);
+                }
+                Err(_) => assert!(!hairpin),
+            }
+        }
+    }
+}
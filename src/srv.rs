@@ -0,0 +1,563 @@
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::net::IpAddr;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use rand::Rng;
+
+use crate::utils::join_host_port;
+use crate::Client;
+
+// Server discovery through DNS SRV records (RFC 5389 section 9, RFC 2782).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Service {
+    Stun,  // _stun._udp
+    Stuns, // _stuns._tcp
+    Turn,  // _turn._udp
+    Turns, // _turns._tcp
+}
+
+impl Service {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Service::Stun => "_stun._udp",
+            Service::Stuns => "_stuns._tcp",
+            Service::Turn => "_turn._udp",
+            Service::Turns => "_turns._tcp",
+        }
+    }
+
+    // Port used when the domain has no SRV records.
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Service::Stun | Service::Turn => 3478,
+            Service::Stuns | Service::Turns => 5349,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+impl SrvRecord {
+    pub fn new(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port,
+            target: target.to_string(),
+        }
+    }
+}
+
+// Looks up SRV records for a name such as "_stun._udp.example.org".
+// An unknown name gives an empty list, not an error.
+pub trait SrvResolver {
+    fn lookup_srv(&self, name: &str) -> io::Result<Vec<SrvRecord>>;
+}
+
+// In-memory resolver, for tests and static configuration.
+#[derive(Debug, Clone, Default)]
+pub struct StaticResolver {
+    records: HashMap<String, Vec<SrvRecord>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        StaticResolver::default()
+    }
+
+    pub fn insert(&mut self, name: &str, record: SrvRecord) {
+        self.records
+            .entry(name.trim_end_matches('.').to_string())
+            .or_default()
+            .push(record);
+    }
+}
+
+impl SrvResolver for StaticResolver {
+    fn lookup_srv(&self, name: &str) -> io::Result<Vec<SrvRecord>> {
+        Ok(self
+            .records
+            .get(name.trim_end_matches('.'))
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(unix)]
+const DNS_PORT: u16 = 53;
+const DNS_TYPE_SRV: u16 = 33;
+const DNS_CLASS_IN: u16 = 1;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+const DNS_FLAG_TC: u16 = 0x0200;
+const MAX_DNS_MESSAGE: usize = 4096;
+
+// Minimal resolver sending SRV queries over UDP to one nameserver, and again
+// over TCP when the answer doesn't fit in a datagram (RFC 1035 section
+// 4.2.2).
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    pub nameserver: SocketAddr,
+    pub timeout: Duration, // for the whole lookup, TCP retry included
+}
+
+impl DnsResolver {
+    pub fn new(nameserver: SocketAddr) -> DnsResolver {
+        DnsResolver {
+            nameserver,
+            timeout: Duration::from_secs(3),
+        }
+    }
+
+    // Uses the first nameserver of /etc/resolv.conf. Unix only: elsewhere
+    // the system resolver isn't configured through a file, pass the
+    // nameserver to DnsResolver::new.
+    #[cfg(unix)]
+    pub fn from_system() -> io::Result<DnsResolver> {
+        let conf = fs::read_to_string("/etc/resolv.conf")?;
+        conf.lines()
+            .filter_map(|l| l.trim().strip_prefix("nameserver"))
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .map(|ip| DnsResolver::new(SocketAddr::new(ip, DNS_PORT)))
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No nameserver configured"))
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_system() -> io::Result<DnsResolver> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "No /etc/resolv.conf on this platform, use DnsResolver::new",
+        ))
+    }
+
+    fn query_udp(&self, query: &[u8], id: u16, deadline: Instant) -> io::Result<Vec<u8>> {
+        let bind = if self.nameserver.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let conn = UdpSocket::bind(bind)?;
+        conn.connect(self.nameserver)?;
+        conn.send(query)?;
+
+        let mut buf = vec![0u8; MAX_DNS_MESSAGE];
+        loop {
+            // Stray datagrams don't buy the nameserver more time.
+            conn.set_read_timeout(Some(remaining(deadline)?))?;
+            let n = conn.recv(&mut buf)?;
+            // Stray or spoofed answers carry another ID.
+            if n >= 2 && BigEndian::read_u16(&buf[..2]) == id {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+        }
+    }
+
+    // Messages over TCP are preceded by their length.
+    fn query_tcp(&self, query: &[u8], id: u16, deadline: Instant) -> io::Result<Vec<u8>> {
+        let mut conn = TcpStream::connect_timeout(&self.nameserver, remaining(deadline)?)?;
+        let mut msg = vec![0u8; 2];
+        BigEndian::write_u16(&mut msg, query.len() as u16);
+        msg.extend_from_slice(query);
+        conn.set_write_timeout(Some(remaining(deadline)?))?;
+        conn.write_all(&msg)?;
+
+        let mut len = [0u8; 2];
+        conn.set_read_timeout(Some(remaining(deadline)?))?;
+        conn.read_exact(&mut len)?;
+        let mut buf = vec![0u8; BigEndian::read_u16(&len) as usize];
+        conn.set_read_timeout(Some(remaining(deadline)?))?;
+        conn.read_exact(&mut buf)?;
+        if buf.len() < 2 || BigEndian::read_u16(&buf[..2]) != id {
+            return Err(invalid("DNS response ID mismatch"));
+        }
+        Ok(buf)
+    }
+}
+
+// Time left until `deadline`, an error once it has passed.
+fn remaining(deadline: Instant) -> io::Result<Duration> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(d) if !d.is_zero() => Ok(d),
+        _ => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "DNS lookup timed out",
+        )),
+    }
+}
+
+impl SrvResolver for DnsResolver {
+    fn lookup_srv(&self, name: &str) -> io::Result<Vec<SrvRecord>> {
+        let deadline = Instant::now() + self.timeout;
+        let id: u16 = rand::thread_rng().gen();
+        let query = build_srv_query(id, name)?;
+
+        let mut msg = self.query_udp(&query, id, deadline)?;
+        // A truncated answer may miss records, ask again over TCP.
+        if msg.len() >= 4 && BigEndian::read_u16(&msg[2..4]) & DNS_FLAG_TC != 0 {
+            msg = self.query_tcp(&query, id, deadline)?;
+        }
+        parse_srv_response(&msg)
+    }
+}
+
+fn build_srv_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut msg = vec![0u8; 12];
+    BigEndian::write_u16(&mut msg[0..2], id);
+    BigEndian::write_u16(&mut msg[2..4], 0x0100); // recursion desired
+    BigEndian::write_u16(&mut msg[4..6], 1); // one question
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid DNS name {}", name),
+            ));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    let mut tail = [0u8; 4];
+    BigEndian::write_u16(&mut tail[..2], DNS_TYPE_SRV);
+    BigEndian::write_u16(&mut tail[2..], DNS_CLASS_IN);
+    msg.extend_from_slice(&tail);
+    Ok(msg)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn parse_srv_response(msg: &[u8]) -> io::Result<Vec<SrvRecord>> {
+    if msg.len() < 12 {
+        return Err(invalid("DNS response too short"));
+    }
+    let flags = BigEndian::read_u16(&msg[2..4]);
+    if flags & 0x8000 == 0 {
+        return Err(invalid("DNS message is not a response"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        DNS_RCODE_NXDOMAIN => return Ok(Vec::new()),
+        rcode => return Err(invalid(&format!("DNS error code {}", rcode))),
+    }
+    let qdcount = BigEndian::read_u16(&msg[4..6]);
+    let ancount = BigEndian::read_u16(&msg[6..8]);
+
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        let (_, next) = read_name(msg, offset).ok_or_else(|| invalid("Bad DNS question"))?;
+        offset = next + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..ancount {
+        let (_, next) = read_name(msg, offset).ok_or_else(|| invalid("Bad DNS answer"))?;
+        let header = msg
+            .get(next..next + 10)
+            .ok_or_else(|| invalid("DNS answer truncated"))?;
+        let rtype = BigEndian::read_u16(&header[0..2]);
+        let rdlength = BigEndian::read_u16(&header[8..10]) as usize;
+        let rdata_start = next + 10;
+        let rdata = msg
+            .get(rdata_start..rdata_start + rdlength)
+            .ok_or_else(|| invalid("DNS answer truncated"))?;
+        // CNAMEs and other records may come along, only SRV is of interest.
+        if rtype == DNS_TYPE_SRV && rdata.len() >= 7 {
+            let (target, _) =
+                read_name(msg, rdata_start + 6).ok_or_else(|| invalid("Bad SRV target"))?;
+            records.push(SrvRecord {
+                priority: BigEndian::read_u16(&rdata[0..2]),
+                weight: BigEndian::read_u16(&rdata[2..4]),
+                port: BigEndian::read_u16(&rdata[4..6]),
+                target,
+            });
+        }
+        offset = rdata_start + rdlength;
+    }
+    Ok(records)
+}
+
+// Reads a possibly compressed name, returning it and the offset just past
+// it in the original position. The root name is returned as ".".
+fn read_name(msg: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Every pointer must go backwards, which also rules out loops.
+    let mut limit = offset;
+    loop {
+        let len = *msg.get(offset)? as usize;
+        match len & 0xc0 {
+            0x00 => {
+                if len == 0 {
+                    let end = end.unwrap_or(offset + 1);
+                    if labels.is_empty() {
+                        return Some((".".to_string(), end));
+                    }
+                    return Some((labels.join("."), end));
+                }
+                let label = msg.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+            0xc0 => {
+                let pointer = (BigEndian::read_u16(msg.get(offset..offset + 2)?) & 0x3fff) as usize;
+                if pointer >= limit {
+                    return None;
+                }
+                end.get_or_insert(offset + 2);
+                limit = pointer;
+                offset = pointer;
+            }
+            _ => return None,
+        }
+    }
+}
+
+// Orders records per RFC 2782: by priority, then by a weighted random
+// choice within each priority.
+pub fn order_records<R: Rng + ?Sized>(mut records: Vec<SrvRecord>, rng: &mut R) -> Vec<SrvRecord> {
+    // Zero weight records go first so they only get a small chance.
+    records.sort_by_key(|r| (r.priority, r.weight != 0));
+
+    let mut ordered = Vec::with_capacity(records.len());
+    let mut i = 0;
+    while i < records.len() {
+        let priority = records[i].priority;
+        let mut group: Vec<SrvRecord> = Vec::new();
+        while i < records.len() && records[i].priority == priority {
+            group.push(records[i].clone());
+            i += 1;
+        }
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|r| r.weight as u32).sum();
+            let pick = rng.gen_range(0..=total);
+            let mut running = 0;
+            let mut index = group.len() - 1;
+            for (j, r) in group.iter().enumerate() {
+                running += r.weight as u32;
+                if running >= pick {
+                    index = j;
+                    break;
+                }
+            }
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+// Resolves `domain` to an ordered list of "host:port" server addresses.
+// Without SRV records (or if the lookup fails) the domain itself is used on
+// the service's default port; a single "." target means the service is
+// explicitly not offered.
+pub fn resolve_servers<R: SrvResolver + ?Sized>(
+    resolver: &R,
+    domain: &str,
+    service: Service,
+) -> Vec<String> {
+    let domain = domain.trim_end_matches('.');
+    let name = format!("{}.{}", service.label(), domain);
+    let records = resolver.lookup_srv(&name).unwrap_or_default();
+
+    if records.is_empty() {
+        return vec![join_host_port(domain, &service.default_port().to_string())];
+    }
+    if records.len() == 1 && records[0].target == "." {
+        return Vec::new();
+    }
+
+    order_records(records, &mut rand::thread_rng())
+        .into_iter()
+        .filter(|r| r.target != ".")
+        .map(|r| join_host_port(r.target.trim_end_matches('.'), &r.port.to_string()))
+        .collect()
+}
+
+impl Client {
+    // Client for the STUN servers `domain` advertises under _stun._udp.
+    pub fn with_srv<R: SrvResolver + ?Sized>(
+        domain: &str,
+        resolver: &R,
+        local_ip: String,
+        local_port: u16,
+        software_name: String,
+    ) -> io::Result<Client> {
+        let servers = resolve_servers(resolver, domain, Service::Stun);
+        if servers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} offers no STUN service", domain),
+            ));
+        }
+        Client::with_servers(servers, local_ip, local_port, software_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn resolve_servers_test() {
+        let mut resolver = StaticResolver::new();
+        resolver.insert(
+            "_stun._udp.example.org",
+            SrvRecord::new(20, 0, 3478, "b.example.org."),
+        );
+        resolver.insert(
+            "_stun._udp.example.org",
+            SrvRecord::new(10, 5, 3479, "a.example.org."),
+        );
+        resolver.insert("_stuns._tcp.example.org", SrvRecord::new(0, 0, 0, "."));
+
+        let servers = resolve_servers(&resolver, "example.org", Service::Stun);
+        assert_eq!(servers, vec!["a.example.org:3479", "b.example.org:3478"]);
+
+        // No records: fall back to the default port.
+        assert_eq!(
+            resolve_servers(&resolver, "example.org", Service::Turn),
+            vec!["example.org:3478"]
+        );
+        assert_eq!(
+            resolve_servers(&resolver, "example.net", Service::Turns),
+            vec!["example.net:5349"]
+        );
+        // "." target: service decidedly not available.
+        assert!(resolve_servers(&resolver, "example.org", Service::Stuns).is_empty());
+    }
+
+    #[test]
+    fn order_records_weight_test() {
+        let records = vec![
+            SrvRecord::new(1, 0, 1, "zero"),
+            SrvRecord::new(1, 90, 1, "heavy"),
+            SrvRecord::new(1, 10, 1, "light"),
+            SrvRecord::new(0, 0, 1, "first"),
+        ];
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let ordered = order_records(records.clone(), &mut rng);
+            assert_eq!(ordered.len(), 4);
+            assert_eq!(ordered[0].target, "first");
+            if ordered[1].target == "heavy" {
+                heavy_first += 1;
+            }
+        }
+        assert!(
+            heavy_first > 800,
+            "heavy picked first {} times",
+            heavy_first
+        );
+    }
+
+    // Answer to `query` with one SRV record, a.example.org:3478.
+    fn srv_response(query: &[u8]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] = 0x81; // response, recursion desired
+        msg[3] = 0x80; // recursion available, no error
+        msg[7] = 1; // one answer
+        msg.extend_from_slice(&[0xc0, 12]); // name: pointer to the question
+        msg.extend_from_slice(&[0, 33, 0, 1, 0, 0, 0, 60]); // SRV IN ttl
+        let target = [1, b'a', 0xc0, 23]; // "a" + pointer to "example.org"
+        msg.extend_from_slice(&[0, 6 + target.len() as u8]);
+        msg.extend_from_slice(&[0, 10, 0, 5, 0x0d, 0x96]);
+        msg.extend_from_slice(&target);
+        msg
+    }
+
+    #[test]
+    fn parse_srv_response_test() {
+        let query = build_srv_query(0x1234, "_stun._udp.example.org").unwrap();
+        let mut msg = srv_response(&query);
+
+        let records = parse_srv_response(&msg).unwrap();
+        assert_eq!(records, vec![SrvRecord::new(10, 5, 3478, "a.example.org")]);
+
+        msg[3] = 0x83; // NXDOMAIN
+        assert!(parse_srv_response(&msg).unwrap().is_empty());
+        assert!(parse_srv_response(&query).is_err());
+    }
+
+    // The UDP answer is truncated and empty, the full one comes over TCP.
+    #[test]
+    fn truncated_test() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let nameserver = udp.local_addr().unwrap();
+        let tcp = std::net::TcpListener::bind(nameserver).unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (n, from) = udp.recv_from(&mut buf).unwrap();
+            let mut msg = buf[..n].to_vec();
+            msg[2] = 0x83; // response, truncated, recursion desired
+            msg[3] = 0x80;
+            udp.send_to(&msg, from).unwrap();
+
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut query = vec![0u8; BigEndian::read_u16(&len) as usize];
+            stream.read_exact(&mut query).unwrap();
+            let msg = srv_response(&query);
+            stream.write_all(&(msg.len() as u16).to_be_bytes()).unwrap();
+            stream.write_all(&msg).unwrap();
+        });
+
+        let records = DnsResolver::new(nameserver)
+            .lookup_srv("_stun._udp.example.org")
+            .unwrap();
+        assert_eq!(records, vec![SrvRecord::new(10, 5, 3478, "a.example.org")]);
+    }
+
+    // Answers with the wrong ID keep coming, the lookup still gives up on
+    // time.
+    #[test]
+    fn deadline_test() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let nameserver = udp.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (n, from) = udp.recv_from(&mut buf).unwrap();
+            let mut msg = srv_response(&buf[..n]);
+            msg[0] ^= 0xff;
+            for _ in 0..40 {
+                if udp.send_to(&msg, from).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut resolver = DnsResolver::new(nameserver);
+        resolver.timeout = Duration::from_millis(300);
+        let start = Instant::now();
+        let e = resolver.lookup_srv("_stun._udp.example.org").unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        ));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn read_name_loop_test() {
+        let msg = [0u8, 0, 0xc0, 2];
+        assert!(read_name(&msg, 2).is_none());
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, trace};
+
+use crate::demux::{classify, DatagramClass};
+use crate::transport::{is_icmp_error, send_to};
+use crate::utils::to_hex;
+use crate::{Class, DecodeMode, Host, Packet, Response, RetransmitPolicy, RttEstimate};
+
+use super::Client;
+
+// Many outstanding transactions over one socket (RFC 5389 section 7).
+//
+// Nothing runs in the background: `poll` sends the retransmissions that are
+// due, reads the socket until the next timer fires and returns what
+// happened. Datagrams that aren't STUN are handed back as Event::Data, so
+// the socket can carry the application's traffic too.
+pub struct TransactionManager {
+    conn: Arc<UdpSocket>,
+    pub policy: RetransmitPolicy,
+    pending: HashMap<[u8; 16], Transaction>,
+    rtt_estimates: HashMap<SocketAddr, RttEstimate>,
+    events: VecDeque<Event>,
+    recv_buffer: Vec<u8>,
+}
+
+struct Transaction {
+    addr: SocketAddr,
+    request: Vec<u8>,
+    attempt: u32, // transmissions so far, minus one
+    rto: Duration,
+    started: Instant,
+    sent_at: Instant,
+    next: Instant, // retransmission or timeout
+    deadline: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    // A response matching an outstanding transaction.
+    Response {
+        trans_id: [u8; 16],
+        response: Box<Response>,
+        rtt: Duration, // since the first transmission
+        retransmissions: u32,
+    },
+    // No response after the last transmission.
+    Timeout {
+        trans_id: [u8; 16],
+        addr: SocketAddr,
+    },
+    // A retransmission could not be sent, the transaction is dropped.
+    Error {
+        trans_id: [u8; 16],
+        addr: SocketAddr,
+        error: io::Error,
+    },
+    // A STUN request or indication from a peer, e.g. an ICE connectivity
+    // check or a keepalive.
+    Request {
+        packet: Packet,
+        from: SocketAddr,
+    },
+    // Anything that isn't STUN, classified as in RFC 7983.
+    Data {
+        data: Vec<u8>,
+        from: SocketAddr,
+        class: DatagramClass,
+    },
+}
+
+impl TransactionManager {
+    pub fn new(conn: Arc<UdpSocket>, policy: RetransmitPolicy) -> TransactionManager {
+        TransactionManager {
+            conn,
+            policy,
+            pending: HashMap::new(),
+            rtt_estimates: HashMap::new(),
+            events: VecDeque::new(),
+            recv_buffer: vec![0u8; crate::net::DEFAULT_RECV_BUFFER_SIZE],
+        }
+    }
+
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.recv_buffer.resize(size, 0);
+    }
+
+    // Sends the first transmission of `pkt` and tracks it until a response
+    // or a timeout is returned by `poll`.
+    pub fn start(&mut self, pkt: &Packet, addr: SocketAddr) -> io::Result<[u8; 16]> {
+        if self.pending.contains_key(&pkt.trans_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Transaction already in flight",
+            ));
+        }
+        let request = pkt.bytes();
+        send_to(&self.conn, &request, addr)?;
+        trace!(server = %addr, trans_id = %to_hex(&pkt.trans_id), "transaction started");
+
+        let now = Instant::now();
+        let rto = self.policy.rto(self.rtt_estimates.get(&addr));
+        let deadline = self.policy.deadline(now);
+        let next = self.policy.timeout_at(0, rto, now, deadline);
+        self.pending.insert(
+            pkt.trans_id,
+            Transaction {
+                addr,
+                request,
+                attempt: 0,
+                rto,
+                started: now,
+                sent_at: now,
+                next,
+                deadline,
+            },
+        );
+        Ok(pkt.trans_id)
+    }
+
+    // Stops tracking a transaction, a late response is then ignored.
+    pub fn cancel(&mut self, trans_id: &[u8; 16]) -> bool {
+        self.pending.remove(trans_id).is_some()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_pending(&self, trans_id: &[u8; 16]) -> bool {
+        self.pending.contains_key(trans_id)
+    }
+
+    pub fn rtt_estimate(&self, addr: &SocketAddr) -> Option<RttEstimate> {
+        self.rtt_estimates.get(addr).copied()
+    }
+
+    // When the earliest timer fires.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.pending.values().map(|t| t.next).min()
+    }
+
+    // Retransmits or times out every transaction whose timer fired.
+    pub fn handle_timeouts(&mut self, now: Instant) {
+        let due: Vec<[u8; 16]> = self
+            .pending
+            .iter()
+            .filter(|(_, t)| t.next <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for trans_id in due {
+            let t = match self.pending.get_mut(&trans_id) {
+                Some(t) => t,
+                None => continue,
+            };
+            if !self.policy.may_retransmit(t.attempt, now, t.deadline) {
+                let addr = t.addr;
+                self.pending.remove(&trans_id);
+                debug!(server = %addr, trans_id = %to_hex(&trans_id), "transaction timed out");
+                self.events.push_back(Event::Timeout { trans_id, addr });
+                continue;
+            }
+
+            t.attempt += 1;
+            if let Err(error) = send_to(&self.conn, &t.request, t.addr) {
+                let addr = t.addr;
+                self.pending.remove(&trans_id);
+                self.events.push_back(Event::Error {
+                    trans_id,
+                    addr,
+                    error,
+                });
+                continue;
+            }
+            trace!(server = %t.addr, trans_id = %to_hex(&trans_id), attempt = t.attempt, "request retransmitted");
+            t.sent_at = now;
+            t.next = self.policy.timeout_at(t.attempt, t.rto, now, t.deadline);
+        }
+    }
+
+    // Matches a received datagram against the outstanding transactions.
+    pub fn handle_datagram(&mut self, data: &[u8], from: SocketAddr) {
+        let class = classify(data);
+        let pkt = match class {
+            DatagramClass::Stun => Packet::decode(data, DecodeMode::Lenient).ok(),
+            _ => None,
+        };
+        let pkt = match pkt {
+            Some(pkt) => pkt,
+            None => {
+                self.events.push_back(Event::Data {
+                    data: data.to_vec(),
+                    from,
+                    class: match class {
+                        DatagramClass::Stun => DatagramClass::Other,
+                        c => c,
+                    },
+                });
+                return;
+            }
+        };
+
+        match pkt.class() {
+            Class::Request | Class::Indication => {
+                self.events.push_back(Event::Request { packet: pkt, from });
+            }
+            Class::SuccessResponse | Class::ErrorResponse => {
+                let t = match self.pending.remove(&pkt.trans_id) {
+                    Some(t) => t,
+                    None => {
+                        trace!(from = %from, trans_id = %to_hex(&pkt.trans_id), "ignoring response to unknown transaction");
+                        return;
+                    }
+                };
+                // Karn's algorithm, only unambiguous samples.
+                if t.attempt == 0 {
+                    self.rtt_estimates
+                        .entry(t.addr)
+                        .or_default()
+                        .update(t.sent_at.elapsed());
+                }
+                let trans_id = pkt.trans_id;
+                // Unknown local address: port 0 never matches a mapping.
+                let local_addr = self
+                    .conn
+                    .local_addr()
+                    .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+                let mut response = Response::new(pkt, &local_addr);
+                response.server_addr = Some(Host::from(from));
+                self.events.push_back(Event::Response {
+                    trans_id,
+                    response: Box::new(response),
+                    rtt: t.started.elapsed(),
+                    retransmissions: t.attempt,
+                });
+            }
+        }
+    }
+
+    // Waits for the next event for at most `timeout`, None waits until
+    // something happens. Ok(None) when nothing did.
+    pub fn poll(&mut self, timeout: Option<Duration>) -> io::Result<Option<Event>> {
+        let until = timeout.map(|t| Instant::now() + t);
+        loop {
+            let now = Instant::now();
+            self.handle_timeouts(now);
+            if let Some(event) = self.events.pop_front() {
+                return Ok(Some(event));
+            }
+
+            let wake = match (self.next_timeout(), until) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            let wait = match wake {
+                Some(wake) if wake <= now => {
+                    if until.is_some_and(|u| u <= now) {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                Some(wake) => Some(wake - now),
+                None => None,
+            };
+            self.conn.set_read_timeout(wait)?;
+
+            match self.conn.recv_from(&mut self.recv_buffer) {
+                Ok((n, from)) => {
+                    let data = self.recv_buffer[..n].to_vec();
+                    self.handle_datagram(&data, from);
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                // The socket is shared by many peers and the error doesn't
+                // say which one is unreachable; its transactions time out.
+                Err(e) if is_icmp_error(&e) => {
+                    debug!(error = %e, "ignoring ICMP error");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Client {
+    // A transaction manager on the client's socket, using its retransmit
+    // policy and receive buffer size.
+    pub fn transaction_manager(&self) -> TransactionManager {
+        let mut manager = TransactionManager::new(self.conn.clone(), self.retransmit_policy);
+        manager.set_recv_buffer_size(self.recv_buffer_size);
+        manager
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TYPE_BINDING_REQUEST, TYPE_BINDING_RESPONSE};
+    use std::thread;
+
+    fn request() -> Packet {
+        let mut pkt = Packet::new();
+        pkt.types = TYPE_BINDING_REQUEST;
+        pkt
+    }
+
+    fn policy() -> RetransmitPolicy {
+        RetransmitPolicy {
+            initial_rto: Duration::from_millis(20),
+            rc: 3,
+            rm: 2,
+            total_timeout: Some(Duration::from_secs(2)),
+            ..RetransmitPolicy::default()
+        }
+    }
+
+    #[test]
+    fn concurrent_transactions_test() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            let mut requests = Vec::new();
+            while requests.len() < 2 {
+                let (n, from) = server.recv_from(&mut buf).unwrap();
+                let pkt = Packet::new_packet_form_bytes(buf[..n].to_vec()).unwrap();
+                if !requests
+                    .iter()
+                    .any(|(p, _): &(Packet, _)| p.trans_id == pkt.trans_id)
+                {
+                    requests.push((pkt, from));
+                }
+            }
+            // Answer out of order, with application data in between.
+            for (pkt, from) in requests.into_iter().rev() {
+                let mut resp = Packet::new();
+                resp.types = TYPE_BINDING_RESPONSE;
+                resp.trans_id = pkt.trans_id;
+                server.send_to(&resp.bytes(), from).unwrap();
+                server.send_to(b"\x80media", from).unwrap();
+            }
+        });
+
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let mut manager = TransactionManager::new(conn, policy());
+        let first = manager.start(&request(), server_addr).unwrap();
+        let second = manager.start(&request(), server_addr).unwrap();
+        assert_eq!(manager.pending(), 2);
+
+        let mut answered = Vec::new();
+        let mut data = 0;
+        while answered.len() < 2 {
+            match manager.poll(Some(Duration::from_secs(2))).unwrap() {
+                Some(Event::Response { trans_id, .. }) => answered.push(trans_id),
+                Some(Event::Data { data: d, class, .. }) => {
+                    assert_eq!(d, b"\x80media");
+                    assert_eq!(class, DatagramClass::Rtp);
+                    data += 1;
+                }
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        responder.join().unwrap();
+        assert_eq!(answered, vec![second, first]);
+        assert!(data >= 1);
+        assert_eq!(manager.pending(), 0);
+    }
+
+    #[test]
+    fn timeout_test() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let conn = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let mut manager = TransactionManager::new(conn, policy());
+        let trans_id = manager
+            .start(&request(), silent.local_addr().unwrap())
+            .unwrap();
+
+        match manager.poll(Some(Duration::from_secs(2))).unwrap() {
+            Some(Event::Timeout { trans_id: t, .. }) => assert_eq!(t, trans_id),
+            other => panic!("unexpected event {:?}", other),
+        }
+        // rc transmissions reached the server.
+        silent.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 100];
+        let mut received = 0;
+        while silent.recv_from(&mut buf).is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 3);
+        assert!(manager
+            .poll(Some(Duration::from_millis(10)))
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn port_unreachable_test() {
+        use std::os::unix::io::AsRawFd;
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let closed = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            let pkt = Packet::new_packet_form_bytes(buf[..n].to_vec()).unwrap();
+            let mut resp = Packet::new();
+            resp.types = TYPE_BINDING_RESPONSE;
+            resp.trans_id = pkt.trans_id;
+            // Give the ICMP error time to reach the client socket first.
+            thread::sleep(Duration::from_millis(50));
+            server.send_to(&resp.bytes(), from).unwrap();
+        });
+
+        // Unconnected sockets only get ICMP errors reported with
+        // IP_RECVERR on Linux.
+        let conn = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let on: libc::c_int = 1;
+        // SAFETY: `on` outlives the call and its size is passed along.
+        let rc = unsafe {
+            libc::setsockopt(
+                conn.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_RECVERR,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(rc, 0);
+        let mut manager = TransactionManager::new(Arc::new(conn), policy());
+        let lost = manager.start(&request(), closed).unwrap();
+        let answered = manager.start(&request(), server_addr).unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            match manager.poll(Some(Duration::from_secs(2))).unwrap() {
+                Some(Event::Response { trans_id, .. }) => events.push(("response", trans_id)),
+                Some(Event::Timeout { trans_id, .. }) => events.push(("timeout", trans_id)),
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+        responder.join().unwrap();
+        assert_eq!(events, vec![("response", answered), ("timeout", lost)]);
+    }
+}
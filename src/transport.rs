@@ -0,0 +1,131 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::ecn::{ecn, set_ecn, Ecn};
+use crate::mtu::set_dont_fragment;
+
+// The datagram socket the client talks through. UdpSocket in real use,
+// sim::SimSocket to run the tests against a simulated NAT.
+pub trait Transport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    // The ECN field of the datagrams sent, Not-ECT for transports without
+    // ECN.
+    fn ecn(&self) -> io::Result<Ecn> {
+        Ok(Ecn::NotEct)
+    }
+
+    // Sets the ECN field of the following datagrams. Transports without
+    // ECN only accept Not-ECT.
+    fn set_ecn(&self, ecn: Ecn) -> io::Result<()> {
+        match ecn {
+            Ecn::NotEct => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "ECN not supported by this transport",
+            )),
+        }
+    }
+
+    // Sets or clears DF on the following datagrams. Transports without it
+    // only accept clearing.
+    fn set_dont_fragment(&self, on: bool) -> io::Result<()> {
+        if !on {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DF not supported by this transport",
+        ))
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn ecn(&self) -> io::Result<Ecn> {
+        ecn(self)
+    }
+
+    fn set_ecn(&self, ecn: Ecn) -> io::Result<()> {
+        set_ecn(self, ecn)
+    }
+
+    fn set_dont_fragment(&self, on: bool) -> io::Result<()> {
+        set_dont_fragment(self, on)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        (**self).send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        (**self).recv_from(buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn ecn(&self) -> io::Result<Ecn> {
+        (**self).ecn()
+    }
+
+    fn set_ecn(&self, ecn: Ecn) -> io::Result<()> {
+        (**self).set_ecn(ecn)
+    }
+
+    fn set_dont_fragment(&self, on: bool) -> io::Result<()> {
+        (**self).set_dont_fragment(on)
+    }
+}
+
+// Port unreachable and the like, reported on a later call on the socket.
+pub(crate) fn is_icmp_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
+// A pending ICMP error fails the send it is reported on, whichever peer it
+// came from. Reporting clears it, the datagram goes out on a second try.
+pub(crate) fn send_to(conn: &dyn Transport, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+    match conn.send_to(buf, addr) {
+        Err(e) if is_icmp_error(&e) => {
+            debug!(server = %addr, error = %e, "ignoring ICMP error");
+            conn.send_to(buf, addr)
+        }
+        result => result,
+    }
+}
@@ -1,9 +1,14 @@
-use local_ip_address::list_afinet_netifas;
 use std::net::SocketAddr;
 
+use crate::interfaces;
+
 pub fn padding(value: &[u8]) -> Vec<u8> {
     let len = value.len();
-    let padding_needed = if len % 4 == 0 { 0 } else { 4 - len % 4 };
+    let padding_needed = if len.is_multiple_of(4) {
+        0
+    } else {
+        4 - len % 4
+    };
     let mut padded_value = Vec::with_capacity(len + padding_needed);
     padded_value.extend_from_slice(value);
     padded_value.resize(len + padding_needed, 0); // 重新大小
@@ -22,47 +27,28 @@ pub fn align(n: u16) -> u16 {
     (n + 3) & 0xfffc
 }
 
-pub fn convert_vec_to_u8_array(vec: &Vec<u8>) -> [u8; 16] {
+pub fn convert_vec_to_u8_array(vec: &[u8]) -> [u8; 16] {
     let mut arry: [u8; 16] = [0; 16];
-
-    for i in 0..vec.len().min(16) {
-        arry[i] = vec[i];
-    }
+    let n = vec.len().min(16);
+    arry[..n].copy_from_slice(&vec[..n]);
     arry
 }
 
-pub fn is_local_addrss(local: &str, local_remote: &str) -> bool {
-    let local_remote_addr = match local_remote.parse::<SocketAddr>() {
-        Ok(addr) => addr.ip().to_string(),
-        Err(_) => return false,
-    };
-
-    match local.parse::<SocketAddr>() {
-        // Ok(addr) =>  addr.ip().to_string(),
-        Ok(addr) => {
-            if addr.ip().is_loopback() {
-                return false;
-            }
-
-            if !addr.ip().is_unspecified() {
-                return addr.ip().to_string() == local_remote_addr;
-            }
-        }
-        Err(_) => return false,
-    };
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-    // .....
-    let network_interfaces = list_afinet_netifas();
-    if let Ok(network_interfaces) = network_interfaces {
-        for (_, ip) in network_interfaces.iter() {
-            if &ip.to_string() == local_remote {
-                return true;
-            }
-        }
-        return false;
-    } else {
+// Whether `mapped` is the socket's own address, i.e. no NAT in between.
+// A socket bound to the unspecified address matches any address of the
+// host's interfaces.
+pub fn is_local_addr(local: &SocketAddr, mapped: &SocketAddr) -> bool {
+    if local.port() != mapped.port() {
         return false;
     }
+    if local.ip().is_unspecified() {
+        return interfaces::is_local_ip(&mapped.ip());
+    }
+    local.ip() == mapped.ip()
 }
 
 #[cfg(test)]
@@ -70,12 +56,49 @@ mod tests {
     use super::*;
 
     #[test]
-    fn paddint_test() {
-        println!("{:?}", padding(&[1u8, 2u8]))
+    fn padding_test() {
+        assert_eq!(padding(&[1u8, 2u8]), [1, 2, 0, 0]);
+        assert_eq!(padding(&[1u8, 2, 3, 4]), [1, 2, 3, 4]);
+        assert!(padding(&[]).is_empty());
+    }
+
+    #[test]
+    fn is_local_addr_test() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(is_local_addr(
+            &addr("192.0.2.1:5000"),
+            &addr("192.0.2.1:5000")
+        ));
+        assert!(!is_local_addr(
+            &addr("192.0.2.1:5000"),
+            &addr("192.0.2.1:5001")
+        ));
+        assert!(!is_local_addr(
+            &addr("192.0.2.1:5000"),
+            &addr("203.0.113.1:5000")
+        ));
+        assert!(is_local_addr(
+            &addr("0.0.0.0:5000"),
+            &addr("127.0.0.1:5000")
+        ));
+        assert!(!is_local_addr(
+            &addr("0.0.0.0:5000"),
+            &addr("203.0.113.1:5000")
+        ));
+        assert!(!is_local_addr(
+            &addr("[::]:5000"),
+            &addr("[2001:db8::1]:5000")
+        ));
+    }
+
+    #[test]
+    fn to_hex_test() {
+        assert_eq!(to_hex(&[0x21, 0x12, 0xa4, 0x42]), "2112a442");
     }
 
     #[test]
     fn join_host_port_test() {
-        println!("{:?}", join_host_port("127.0.0.1", "22"))
+        assert_eq!(join_host_port("127.0.0.1", "22"), "127.0.0.1:22");
+        assert_eq!(join_host_port("::1", "22"), "[::1]:22");
     }
 }